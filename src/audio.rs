@@ -0,0 +1,431 @@
+use crate::{setting_value::SettingValueEnum, settings::Settings, Error};
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{BufRead, BufReader},
+    ops::Range,
+};
+
+const WINDOW_SIZE: usize = 2048;
+
+// (name, low frequency, high frequency) in Hz
+const BANDS: &[(&str, f64, f64)] = &[
+    ("bass", 20.0, 250.0),
+    ("mid", 250.0, 2000.0),
+    ("treble", 2000.0, 16000.0),
+];
+
+// Feature names usable in the mapping file: "amplitude", followed by the band names
+const NUM_FEATURES: usize = 4;
+
+struct Wav {
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    let bytes = data.get(offset..offset + 2).ok_or("Truncated wav file")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = data.get(offset..offset + 4).ok_or("Truncated wav file")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Reads a PCM (8/16/24/32 bit integer) or 32 bit float wav file, mixed down to mono
+fn load_wav(path: &str) -> Result<Wav, Error> {
+    let data = std::fs::read(path)?;
+    if data.get(0..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WAVE") {
+        return Err(format!("Not a wav file: {}", path).into());
+    }
+    let mut format = None;
+    let mut samples_data = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = read_u32(&data, offset + 4)? as usize;
+        let body = offset + 8;
+        let end = (body + size).min(data.len());
+        if id == b"fmt " {
+            let mut audio_format = read_u16(&data, body)?;
+            let channels = read_u16(&data, body + 2)?;
+            let sample_rate = read_u32(&data, body + 4)?;
+            let bits = read_u16(&data, body + 14)?;
+            // WAVE_FORMAT_EXTENSIBLE, the real format is the start of the subformat GUID
+            if audio_format == 0xFFFE {
+                audio_format = read_u16(&data, body + 24)?;
+            }
+            format = Some((audio_format, channels, sample_rate, bits));
+        } else if id == b"data" {
+            samples_data = Some(&data[body..end]);
+        }
+        // chunks are padded to an even size
+        offset = body + size + (size & 1);
+    }
+    let (audio_format, channels, sample_rate, bits) = format.ok_or("wav file has no fmt chunk")?;
+    let samples_data = samples_data.ok_or("wav file has no data chunk")?;
+    let channels = channels.max(1) as usize;
+    let bytes = (bits / 8) as usize;
+    if bytes == 0 {
+        return Err("Invalid wav bits per sample".into());
+    }
+    let decode = |sample: &[u8]| -> Result<f32, Error> {
+        Ok(match (audio_format, bits) {
+            (1, 8) => (sample[0] as f32 - 128.0) / 128.0,
+            (1, 16) => i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+            (1, 24) => {
+                i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) as f32 / 2147483648.0
+            }
+            (1, 32) => {
+                i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32
+                    / 2147483648.0
+            }
+            (3, 32) => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            _ => {
+                return Err(format!(
+                    "Unsupported wav format {} with {} bits per sample",
+                    audio_format, bits
+                )
+                .into())
+            }
+        })
+    };
+    let mut samples = Vec::with_capacity(samples_data.len() / (bytes * channels).max(1));
+    for frame in samples_data.chunks_exact(bytes * channels) {
+        let mut sum = 0.0;
+        for sample in frame.chunks_exact(bytes) {
+            sum += decode(sample)?;
+        }
+        samples.push(sum / channels as f32);
+    }
+    Ok(Wav {
+        sample_rate,
+        samples,
+    })
+}
+
+// In-place iterative radix-2 FFT, `re.len()` must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Per-frame audio features of the rendered frames, each normalized to 0..1 over the whole video
+// so that frame ranges rendered separately match up
+pub struct AudioFeatures {
+    first: usize,
+    frames: Vec<[f64; NUM_FEATURES]>,
+}
+
+impl AudioFeatures {
+    // The whole video of `frames` frames is analyzed for the normalization and the release of
+    // earlier peaks, only `range` is kept
+    pub fn analyze(
+        path: &str,
+        fps: f64,
+        frames: usize,
+        range: Range<usize>,
+        decay: f64,
+    ) -> Result<Self, Error> {
+        let wav = load_wav(path)?;
+        let rate = wav.sample_rate as f64;
+        let mut result = Vec::with_capacity(frames);
+        let mut re = vec![0.0; WINDOW_SIZE];
+        let mut im = vec![0.0; WINDOW_SIZE];
+        for frame in 0..frames {
+            let center = (frame as f64 / fps * rate) as isize;
+            let start = center - WINDOW_SIZE as isize / 2;
+            let mut sum_squares = 0.0;
+            for i in 0..WINDOW_SIZE {
+                let index = start + i as isize;
+                let sample = if index >= 0 {
+                    wav.samples.get(index as usize).copied().unwrap_or(0.0) as f64
+                } else {
+                    0.0
+                };
+                sum_squares += sample * sample;
+                let hann = 0.5 - 0.5 * (2.0 * PI * i as f64 / WINDOW_SIZE as f64).cos();
+                re[i] = sample * hann;
+                im[i] = 0.0;
+            }
+            fft(&mut re, &mut im);
+            let mut features = [0.0; NUM_FEATURES];
+            features[0] = (sum_squares / WINDOW_SIZE as f64).sqrt();
+            for (band, &(_, low, high)) in BANDS.iter().enumerate() {
+                let bin_hz = rate / WINDOW_SIZE as f64;
+                let low = ((low / bin_hz) as usize).max(1);
+                let high = ((high / bin_hz) as usize).min(WINDOW_SIZE / 2);
+                let energy: f64 = (low..high.max(low))
+                    .map(|bin| re[bin] * re[bin] + im[bin] * im[bin])
+                    .sum();
+                features[band + 1] = energy.sqrt();
+            }
+            result.push(features);
+        }
+        for feature in 0..NUM_FEATURES {
+            let max = result.iter().map(|f| f[feature]).fold(0.0, f64::max);
+            let mut previous = 0.0;
+            for frame in &mut result {
+                let value = if max > 0.0 { frame[feature] / max } else { 0.0 };
+                // instant attack, exponential release
+                previous = f64::max(value, previous * decay);
+                frame[feature] = previous;
+            }
+        }
+        let range = range.start.min(frames)..range.end.min(frames);
+        Ok(Self {
+            first: range.start,
+            frames: result[range].to_vec(),
+        })
+    }
+
+    fn get(&self, frame: usize, feature: usize) -> f64 {
+        frame
+            .checked_sub(self.first)
+            .and_then(|frame| self.frames.get(frame))
+            .map_or(0.0, |f| f[feature])
+    }
+}
+
+fn feature_index(name: &str) -> Option<usize> {
+    if name == "amplitude" {
+        Some(0)
+    } else {
+        BANDS
+            .iter()
+            .position(|&(band, _, _)| band == name)
+            .map(|i| i + 1)
+    }
+}
+
+struct Mapping {
+    key: String,
+    feature: usize,
+    amount: SettingValueEnum,
+}
+
+// Maps audio features onto settings, added on top of the animated value.
+//
+// File format:
+//   decay = 0.8
+//   scale = bass * 0.25
+//   light1_color = treble * 1 0.5 0.25
+pub struct AudioMapping {
+    mappings: Vec<Mapping>,
+    pub decay: f64,
+}
+
+impl AudioMapping {
+    pub fn load(file: &str, reference: &Settings) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(file)?);
+        let mut result = Self {
+            mappings: Vec::new(),
+            decay: 0.0,
+        };
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, expr) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid format in audio mapping file: {}", line))?;
+            let key = key.trim();
+            if key == "decay" {
+                result.decay = expr.trim().parse()?;
+                continue;
+            }
+            let (feature, amount) = expr
+                .split_once('*')
+                .ok_or_else(|| format!("Invalid format in audio mapping file: {}", line))?;
+            let feature = feature_index(feature.trim())
+                .ok_or_else(|| format!("Unknown audio feature: {}", feature.trim()))?;
+            let reference = reference
+                .get(key)
+                .ok_or_else(|| format!("Unknown setting in audio mapping file: {}", key))?;
+            if let SettingValueEnum::Text(_) = reference.value() {
+                return Err(format!("Setting can't be driven by audio: {}", key).into());
+            }
+            // Int amounts may be negative, they are rounded after scaling
+            let amount = match reference.value() {
+                SettingValueEnum::Int(_) => SettingValueEnum::Float(amount.trim().parse()?, 0.0),
+                value => value.parse_like(amount.trim())?,
+            };
+            result.mappings.push(Mapping {
+                key: key.to_string(),
+                feature,
+                amount,
+            });
+        }
+        Ok(result)
+    }
+
+    pub fn apply(&self, settings: &mut Settings, features: &AudioFeatures, frame: usize) {
+        for mapping in &self.mappings {
+            let amount = features.get(frame, mapping.feature);
+            let value = settings.find_mut(&mapping.key);
+            let new_value = match (value.value(), &mapping.amount) {
+                (&SettingValueEnum::Int(v), &SettingValueEnum::Float(a, _)) => {
+                    SettingValueEnum::Int((v as f64 + a * amount).round().max(0.0) as u64)
+                }
+                (&SettingValueEnum::Float(v, change), &SettingValueEnum::Float(a, _)) => {
+                    SettingValueEnum::Float(v + a * amount, change)
+                }
+                (&SettingValueEnum::Vec3(v, change), &SettingValueEnum::Vec3(a, _)) => {
+                    SettingValueEnum::Vec3(v + a * amount, change)
+                }
+                _ => panic!("Inconsistent audio mapping types"),
+            };
+            value.set_value(new_value);
+        }
+        settings.normalize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16 bit PCM, `frames` of one sample per channel
+    fn wav_bytes(sample_rate: u32, frames: &[&[i16]]) -> Vec<u8> {
+        let channels = frames[0].len() as u16;
+        let data: Vec<u8> = frames
+            .iter()
+            .flat_map(|frame| frame.iter().flat_map(|sample| sample.to_le_bytes()))
+            .collect();
+        let mut result = Vec::new();
+        result.extend(b"RIFF");
+        result.extend((36 + data.len() as u32).to_le_bytes());
+        result.extend(b"WAVEfmt ");
+        result.extend(16u32.to_le_bytes());
+        result.extend(1u16.to_le_bytes());
+        result.extend(channels.to_le_bytes());
+        result.extend(sample_rate.to_le_bytes());
+        result.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+        result.extend((channels * 2).to_le_bytes());
+        result.extend(16u16.to_le_bytes());
+        result.extend(b"data");
+        result.extend((data.len() as u32).to_le_bytes());
+        result.extend(data);
+        result
+    }
+
+    fn load(name: &str, bytes: &[u8]) -> Result<Wav, Error> {
+        let path = std::env::temp_dir().join(format!("clam5_test_{}.wav", name));
+        std::fs::write(&path, bytes).unwrap();
+        let result = load_wav(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn wav_mixes_to_mono() {
+        let wav = load(
+            "stereo",
+            &wav_bytes(44100, &[&[16384, 0], &[-32768, -32768], &[100, -100]]),
+        )
+        .unwrap();
+        assert_eq!(wav.sample_rate, 44100);
+        assert_eq!(wav.samples, [0.25, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn wav_errors() {
+        assert!(load("not_wav", b"RIFX\0\0\0\0WAVE").is_err());
+        let mut truncated = wav_bytes(8000, &[&[0]]);
+        // drop the data chunk
+        truncated.truncate(36);
+        assert!(load("no_data", &truncated).is_err());
+    }
+
+    #[test]
+    fn fft_of_a_sine() {
+        let n = 64;
+        let bin = 5;
+        let mut re: Vec<f64> = (0..n)
+            .map(|i| (2.0 * PI * bin as f64 * i as f64 / n as f64).sin())
+            .collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let magnitude = re[k].hypot(im[k]);
+            let expected = if k == bin || k == n - bin {
+                n as f64 / 2.0
+            } else {
+                0.0
+            };
+            assert!(
+                (magnitude - expected).abs() < 1e-9,
+                "bin {}: {}",
+                k,
+                magnitude
+            );
+        }
+    }
+
+    #[test]
+    fn frame_ranges_match_the_whole_video() {
+        // a 440 Hz tone getting louder over a second, with a loud burst in the middle
+        let rate = 8000;
+        let samples: Vec<i16> = (0..rate)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                let gain = if (0.5..0.6).contains(&t) {
+                    1.0
+                } else {
+                    t * 0.5
+                };
+                ((2.0 * PI * 440.0 * t).sin() * gain * 32767.0) as i16
+            })
+            .collect();
+        let frames: Vec<&[i16]> = samples.chunks(1).collect();
+        let path = std::env::temp_dir().join("clam5_test_ranges.wav");
+        std::fs::write(&path, wav_bytes(rate as u32, &frames)).unwrap();
+        let path = path.to_str().unwrap();
+        let whole = AudioFeatures::analyze(path, 30.0, 30, 0..30, 0.8).unwrap();
+        let first = AudioFeatures::analyze(path, 30.0, 30, 0..10, 0.8).unwrap();
+        let second = AudioFeatures::analyze(path, 30.0, 30, 10..30, 0.8).unwrap();
+        std::fs::remove_file(path).unwrap();
+        for frame in 0..30 {
+            let part = if frame < 10 { &first } else { &second };
+            for feature in 0..NUM_FEATURES {
+                assert_eq!(part.get(frame, feature), whole.get(frame, feature));
+            }
+        }
+        let loudest = (0..30).map(|frame| whole.get(frame, 0)).fold(0.0, f64::max);
+        assert_eq!(loudest, 1.0);
+        assert!(whole.get(5, 0) < 0.5);
+    }
+}
@@ -23,6 +23,10 @@ fn frag(@location(0) texCoord: vec2<f32>) -> @location(0) vec4<f32> {
     let x = u32(texCoord.x * f32(unis.width));
     let y = u32(texCoord.y * f32(unis.height));
     var value = tex[y * unis.width + x];
+    // the kernel's color is premultiplied by alpha
+    if value.w > 0.0 {
+        value = vec4<f32>(value.xyz / value.w, value.w);
+    }
     if unis.output_srgb != 0u {
         value.x = LinearToSrgb(value.x);
         value.y = LinearToSrgb(value.y);
@@ -0,0 +1,175 @@
+use crate::cast_slice;
+use wgpu::util::DeviceExt;
+
+const ITERATIONS: u32 = 5;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Uniforms {
+    width: u32,
+    height: u32,
+    step: u32,
+    dummy: u32,
+}
+
+// Resources that depend on the image size
+struct Targets {
+    size: (u32, u32),
+    ping_pong: [wgpu::Buffer; 2],
+    // one per iteration, the step size differs
+    uniforms: Vec<wgpu::Buffer>,
+}
+
+// Edge-avoiding a-trous denoiser, guided by the first hit albedo/normal/depth written by the
+// kernel. The output has the same layout as the kernel image, so it can be blitted or downloaded
+// the same way.
+pub struct Denoiser {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    targets: Option<Targets>,
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn buffer_entry(binding: u32, buffer: &wgpu::Buffer) -> wgpu::BindGroupEntry<'_> {
+    wgpu::BindGroupEntry {
+        binding,
+        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+            buffer,
+            offset: 0,
+            size: None,
+        }),
+    }
+}
+
+impl Targets {
+    fn new(device: &wgpu::Device, size: (u32, u32)) -> Self {
+        let buffer = || {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: size.0 as u64 * size.1 as u64 * (4 * 4),
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let uniforms = (0..ITERATIONS)
+            .map(|iteration| {
+                let uniforms = [Uniforms {
+                    width: size.0,
+                    height: size.1,
+                    step: 1 << iteration,
+                    dummy: 0,
+                }];
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents: cast_slice(&uniforms),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                })
+            })
+            .collect();
+        Self {
+            size,
+            ping_pong: [buffer(), buffer()],
+            uniforms,
+        }
+    }
+}
+
+impl Denoiser {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let module = device.create_shader_module(wgpu::include_wgsl!("denoise.wgsl"));
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                storage_entry(0, true),
+                storage_entry(1, false),
+                storage_entry(2, true),
+                storage_entry(3, true),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "main",
+        });
+        Self {
+            pipeline,
+            bind_group_layout,
+            targets: None,
+        }
+    }
+
+    // Records the denoising passes, `output` has the result afterwards
+    pub fn run(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        img: &wgpu::Buffer,
+        aovs: &wgpu::Buffer,
+        stats: &wgpu::Buffer,
+        size: (u32, u32),
+    ) {
+        if self.targets.as_ref().map(|targets| targets.size) != Some(size) {
+            self.targets = Some(Targets::new(device, size));
+        }
+        let targets = self.targets.as_ref().unwrap();
+        for iteration in 0..ITERATIONS as usize {
+            let src = if iteration == 0 {
+                img
+            } else {
+                &targets.ping_pong[(iteration + 1) % 2]
+            };
+            let dst = &targets.ping_pong[iteration % 2];
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &self.bind_group_layout,
+                entries: &[
+                    buffer_entry(0, src),
+                    buffer_entry(1, dst),
+                    buffer_entry(2, aovs),
+                    buffer_entry(3, stats),
+                    buffer_entry(4, &targets.uniforms[iteration]),
+                ],
+            });
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(size.0.div_ceil(8), size.1.div_ceil(8), 1);
+        }
+    }
+
+    pub fn output(&self) -> Option<&wgpu::Buffer> {
+        let targets = self.targets.as_ref()?;
+        Some(&targets.ping_pong[(ITERATIONS as usize - 1) % 2])
+    }
+}
@@ -0,0 +1,103 @@
+// Edge-avoiding a-trous wavelet filter, one iteration per dispatch with the step doubling each
+// time. Guided by the first hit albedo, normal and depth, and by the per pixel noise estimate.
+
+struct Uniforms {
+    width: u32,
+    height: u32,
+    step: u32,
+    dummy: u32,
+}
+
+struct Aov {
+    albedo_depth: vec4<f32>,
+    normal: vec4<f32>,
+    counter_steps: vec4<f32>,
+}
+
+struct PixelStats {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    dummy: u32,
+}
+
+@group(0) @binding(0)
+var<storage, read> src: array<vec4<f32>>;
+@group(0) @binding(1)
+var<storage, read_write> dst: array<vec4<f32>>;
+@group(0) @binding(2)
+var<storage, read> aovs: array<Aov>;
+@group(0) @binding(3)
+var<storage, read> stats: array<PixelStats>;
+@group(0) @binding(4)
+var<uniform> unis: Uniforms;
+
+const NORMAL_POWER: f32 = 64.0;
+const DEPTH_SIGMA: f32 = 0.05;
+const ALBEDO_SIGMA: f32 = 0.1;
+const COLOR_SIGMA: f32 = 4.0;
+
+fn Luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn StandardError(pixel: PixelStats) -> f32 {
+    if pixel.count < 2u {
+        return 1.0;
+    }
+    let n = f32(pixel.count);
+    return sqrt(pixel.m2 / (n - 1.0) / n);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let x = global_invocation_id.x;
+    let y = global_invocation_id.y;
+    if x >= unis.width || y >= unis.height {
+        return;
+    }
+    let idx = y * unis.width + x;
+    let color = src[idx];
+    let aov = aovs[idx];
+    let albedo = aov.albedo_depth.xyz;
+    let depth = aov.albedo_depth.w;
+    let normal = normalize(aov.normal.xyz + vec3<f32>(0.0, 0.0, 1e-6));
+    let luminance = Luminance(color.xyz);
+    // the noise shrinks with every iteration
+    let color_sigma = COLOR_SIGMA * StandardError(stats[idx]) / sqrt(f32(unis.step)) + 1e-3;
+
+    // B3 spline
+    var weights = array<f32, 3>(0.375, 0.25, 0.0625);
+    // color is premultiplied when transparent, so alpha is filtered along with it
+    var sum = vec3<f32>(0.0);
+    var alpha_sum = 0.0;
+    var weight_sum = 0.0;
+    for (var dy = -2; dy <= 2; dy++) {
+        for (var dx = -2; dx <= 2; dx++) {
+            let qx = i32(x) + dx * i32(unis.step);
+            let qy = i32(y) + dy * i32(unis.step);
+            if qx < 0 || qy < 0 || qx >= i32(unis.width) || qy >= i32(unis.height) {
+                continue;
+            }
+            let q = u32(qy) * unis.width + u32(qx);
+            let q_color = src[q].xyz;
+            let q_alpha = src[q].w;
+            let q_aov = aovs[q];
+            let q_normal = normalize(q_aov.normal.xyz + vec3<f32>(0.0, 0.0, 1e-6));
+            let albedo_diff = q_aov.albedo_depth.xyz - albedo;
+            let w_normal = pow(max(dot(normal, q_normal), 0.0), NORMAL_POWER);
+            let w_depth = exp(-abs(q_aov.albedo_depth.w - depth) / (DEPTH_SIGMA * max(depth, 1e-6) * f32(unis.step)));
+            let w_albedo = exp(-dot(albedo_diff, albedo_diff) / ALBEDO_SIGMA);
+            let w_color = exp(-abs(Luminance(q_color) - luminance) / color_sigma);
+            let w = weights[abs(dx)] * weights[abs(dy)] * w_normal * w_depth * w_albedo * w_color;
+            sum += q_color * w;
+            alpha_sum += q_alpha * w;
+            weight_sum += w;
+        }
+    }
+    if weight_sum > 0.0 {
+        dst[idx] = vec4<f32>(sum / weight_sum, alpha_sum / weight_sum);
+    } else {
+        dst[idx] = color;
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    video_profile::{OutputKind, VideoProfile},
+    CpuTexture, Error,
+};
+use color_quant::NeuQuant;
+use png::{BitDepth, ColorType};
+use std::{fs::File, io::BufWriter};
+
+// Sampling factor for NeuQuant palette training, 1 is best and slowest, 30 is worst and fastest
+const GIF_QUANTIZE_SAMPLING: i32 = 10;
+
+// Gif has no partial transparency, pixels below this alpha become fully transparent
+const GIF_ALPHA_THRESHOLD: u8 = 128;
+
+// Palette of a single gif frame, plus the indexed pixels. With alpha, the last palette entry is
+// reserved for transparent pixels.
+fn quantize(image: &CpuTexture, dither: bool) -> (Vec<u8>, Vec<u8>) {
+    let channels = if image.alpha { 4 } else { 3 };
+    let rgba = image
+        .data
+        .chunks_exact(channels)
+        .flat_map(|px| [px[0], px[1], px[2], 255])
+        .collect::<Vec<_>>();
+    let colors = if image.alpha { 255 } else { 256 };
+    let quant = NeuQuant::new(GIF_QUANTIZE_SAMPLING, colors, &rgba);
+    let mut palette = quant.color_map_rgb();
+    let transparent = |i: usize| image.alpha && image.data[i * 4 + 3] < GIF_ALPHA_THRESHOLD;
+    let (width, height) = (image.size.0 as usize, image.size.1 as usize);
+    let mut indices = Vec::with_capacity(width * height);
+    if image.alpha {
+        palette.extend_from_slice(&[0, 0, 0]);
+    }
+    if !dither {
+        indices.extend(rgba.chunks_exact(4).enumerate().map(|(i, px)| {
+            if transparent(i) {
+                colors as u8
+            } else {
+                quant.index_of(px) as u8
+            }
+        }));
+        return (palette, indices);
+    }
+    // Floyd-Steinberg, the error of the current and next row is carried along
+    let mut error = vec![[0.0f32; 3]; width * 2];
+    for y in 0..height {
+        let (current, next) = error.split_at_mut(width);
+        for x in 0..width {
+            if transparent(y * width + x) {
+                indices.push(colors as u8);
+                continue;
+            }
+            let source = &rgba[(y * width + x) * 4..][..3];
+            let mut wanted = [0.0; 3];
+            let mut pixel = [0, 0, 0, 255];
+            for c in 0..3 {
+                wanted[c] = source[c] as f32 + current[x][c];
+                pixel[c] = wanted[c].round().clamp(0.0, 255.0) as u8;
+            }
+            let index = quant.index_of(&pixel);
+            indices.push(index as u8);
+            for c in 0..3 {
+                let err = wanted[c] - palette[index * 3 + c] as f32;
+                if x + 1 < width {
+                    current[x + 1][c] += err * 7.0 / 16.0;
+                    next[x + 1][c] += err * 1.0 / 16.0;
+                }
+                if x > 0 {
+                    next[x - 1][c] += err * 3.0 / 16.0;
+                }
+                next[x][c] += err * 5.0 / 16.0;
+            }
+        }
+        error.copy_within(width.., 0);
+        error[width..].fill([0.0; 3]);
+    }
+    (palette, indices)
+}
+
+// Encodes animation frames straight into a file, without ffmpeg or an intermediate png sequence
+pub enum AnimationWriter {
+    Apng(png::Writer<BufWriter<File>>),
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        // in centiseconds
+        delay: u16,
+        dither: bool,
+        alpha: bool,
+    },
+}
+
+impl AnimationWriter {
+    pub fn new(
+        profile: &VideoProfile,
+        path: &str,
+        size: (u32, u32),
+        frames: usize,
+        alpha: bool,
+    ) -> Result<Self, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        match profile.kind {
+            OutputKind::Apng => {
+                let mut encoder = png::Encoder::new(file, size.0, size.1);
+                encoder.set_color(if alpha {
+                    ColorType::Rgba
+                } else {
+                    ColorType::Rgb
+                });
+                encoder.set_depth(BitDepth::Eight);
+                encoder.set_animated(frames as u32, 0)?;
+                let denominator = (profile.fps * 100.0).round().clamp(1.0, u16::MAX as f64);
+                encoder.set_frame_delay(100, denominator as u16)?;
+                Ok(AnimationWriter::Apng(encoder.write_header()?))
+            }
+            OutputKind::Gif => {
+                if size.0 > u16::MAX as u32 || size.1 > u16::MAX as u32 {
+                    return Err("Image too large for gif".into());
+                }
+                let mut encoder = gif::Encoder::new(file, size.0 as u16, size.1 as u16, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Ok(AnimationWriter::Gif {
+                    encoder,
+                    delay: (100.0 / profile.fps).round().max(1.0) as u16,
+                    dither: profile.dither,
+                    alpha,
+                })
+            }
+            _ => Err("Not an animation format".into()),
+        }
+    }
+
+    pub fn write_frame(&mut self, image: &CpuTexture) -> Result<(), Error> {
+        match self {
+            AnimationWriter::Apng(writer) => writer.write_image_data(&image.data)?,
+            AnimationWriter::Gif {
+                encoder,
+                delay,
+                dither,
+                alpha,
+            } => {
+                let (palette, indices) = quantize(image, *dither);
+                let mut frame = gif::Frame::from_indexed_pixels(
+                    image.size.0 as u16,
+                    image.size.1 as u16,
+                    indices,
+                    None,
+                );
+                frame.palette = Some(palette);
+                frame.delay = *delay;
+                if *alpha {
+                    frame.transparent = Some(255);
+                    // otherwise the previous frame shows through the transparent pixels
+                    frame.dispose = gif::DisposalMethod::Background;
+                }
+                encoder.write_frame(&frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            AnimationWriter::Apng(writer) => writer.finish()?,
+            AnimationWriter::Gif { encoder, .. } => {
+                encoder.into_inner()?;
+            }
+        }
+        Ok(())
+    }
+}
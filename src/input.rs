@@ -1,5 +1,14 @@
 use crate::{
-    keyframe_list::KeyframeList, settings::Settings, settings_input::SettingsInput, Error, Key,
+    kernel_uniforms::{
+        light_key, LIGHT_CONSTANT, LIGHT_DIRECTIONAL, LIGHT_OFF, LIGHT_POINT, LIGHT_SPHERE,
+        LIGHT_SPOT, MAX_LIGHTS,
+    },
+    keyframe_list::{KeyframeList, PathSpeed, PathTiming},
+    setting_value::SettingValueEnum,
+    settings::Settings,
+    settings_input::SettingsInput,
+    track_list::TrackList,
+    Error, Key,
 };
 use cgmath::{prelude::*, Quaternion, Rad, Vector3};
 use instant::Instant;
@@ -10,8 +19,14 @@ pub struct Input {
     pressed_keys: HashMap<Key, Instant>,
     spaceship: Option<(Vector3<f64>, Vector3<f64>, Instant)>,
     cur_video_secs: f64,
-    video_len_secs: f64,
+    playing: bool,
+    keyframe_index: usize,
+    path_speed: PathSpeed,
+    path_timing: PathTiming,
+    pub tracks: Option<TrackList>,
     last_update: Instant,
+    pub show_path: bool,
+    pub denoise: bool,
     pub settings_input: SettingsInput,
 }
 
@@ -21,25 +36,34 @@ impl Input {
             pressed_keys: HashMap::new(),
             spaceship: None,
             cur_video_secs: 0.0,
-            video_len_secs: 0.0,
+            playing: false,
+            keyframe_index: 0,
+            path_speed: PathSpeed::Keyframe,
+            path_timing: PathTiming::identity(),
+            tracks: Self::load_tracks(),
             last_update: Instant::now(),
+            show_path: false,
+            denoise: false,
             settings_input: SettingsInput::new(),
         }
     }
 
     fn help() {
         info!("Keybindings:");
-        // free:
-        // QE
-        //
-        // CB
+        // free: no letters left
         info!("WASD, [space]Z, IJKL, OU: move camera");
         info!("RF: focal distance/move speed");
         info!("NM: field of view");
-        info!("Y: Write settings to disk. P: Read settings. V: Write keyframe. G: Play keyframes.");
+        info!("Y: Write settings to disk. P: Read settings. V: Write keyframe.");
+        info!("G: Play keyframes, or tracks.clam5 if it exists (reloaded on every press)");
+        info!("C: Cycle keyframe playback speed (per keyframe/constant/scaled)");
+        info!("PageUp/PageDown: Previous/next keyframe. QE: Scrub keyframe timeline.");
+        info!("Enter: Replace keyframe. Insert: Insert keyframe after. Delete: Delete keyframe.");
+        info!("[]: Move keyframe earlier/later. B: Show camera path (perspective only).");
         info!("up/down/left/right: Adjust settings. T: Toggle zero setting.");
-        info!("X: Copy position to lightsource position");
+        info!("X: Move the light whose setting is selected (or light 1) to the camera");
         info!("`: Spaceship!");
+        info!("F2: Toggle denoiser");
         info!("H: Print this message");
     }
 
@@ -100,23 +124,81 @@ impl Input {
             }
             Key::KeyV => {
                 keyframes.push(settings.clone());
-                keyframes.save("keyframes.clam5", default_settings)?;
+                self.keyframe_index = keyframes.len() - 1;
+                self.keyframes_changed(keyframes, default_settings)?;
                 info!("Keyframe saved");
             }
             Key::KeyG => {
                 self.cur_video_secs = 0.0;
-                self.video_len_secs = keyframes.len() as f64 * (10.0 / 6.0);
-                info!("Playing video")
+                self.playing = true;
+                self.tracks = Self::load_tracks();
+                self.update_timing(keyframes);
+                if self.tracks.is_some() {
+                    info!("Playing tracks.clam5")
+                } else {
+                    info!("Playing video")
+                }
+            }
+            Key::KeyC => {
+                self.path_speed = self.path_speed.next();
+                self.update_timing(keyframes);
+                info!("Path speed: {:?}", self.path_speed);
+            }
+            Key::KeyB => {
+                self.show_path = !self.show_path;
+            }
+            Key::F2 => {
+                self.denoise = !self.denoise;
+                info!("Denoiser: {}", if self.denoise { "on" } else { "off" });
+            }
+            Key::PageUp if self.keyframe_index > 0 => {
+                self.jump_to_keyframe(self.keyframe_index - 1, settings, keyframes);
+            }
+            Key::PageDown if self.keyframe_index + 1 < keyframes.len() => {
+                self.jump_to_keyframe(self.keyframe_index + 1, settings, keyframes);
+            }
+            Key::Enter if self.keyframe_index < keyframes.len() => {
+                keyframes.replace(self.keyframe_index, settings.clone());
+                self.keyframes_changed(keyframes, default_settings)?;
+                info!("Keyframe {} replaced", self.keyframe_index);
+            }
+            Key::Insert => {
+                let index = if keyframes.len() == 0 {
+                    0
+                } else {
+                    self.keyframe_index + 1
+                };
+                keyframes.insert(index, settings.clone());
+                self.keyframe_index = index;
+                self.keyframes_changed(keyframes, default_settings)?;
+                info!("Keyframe {} inserted", self.keyframe_index);
+            }
+            Key::Delete if self.keyframe_index < keyframes.len() => {
+                keyframes.remove(self.keyframe_index);
+                info!("Keyframe {} deleted", self.keyframe_index);
+                self.keyframe_index = self.keyframe_index.min(keyframes.len().max(1) - 1);
+                self.keyframes_changed(keyframes, default_settings)?;
+            }
+            Key::BracketLeft
+                if self.keyframe_index > 0 && self.keyframe_index < keyframes.len() =>
+            {
+                keyframes.swap(self.keyframe_index, self.keyframe_index - 1);
+                self.keyframe_index -= 1;
+                self.keyframes_changed(keyframes, default_settings)?;
+                info!("Keyframe moved to {}", self.keyframe_index);
+            }
+            Key::BracketRight if self.keyframe_index + 1 < keyframes.len() => {
+                keyframes.swap(self.keyframe_index, self.keyframe_index + 1);
+                self.keyframe_index += 1;
+                self.keyframes_changed(keyframes, default_settings)?;
+                info!("Keyframe moved to {}", self.keyframe_index);
             }
             Key::ArrowUp => self.settings_input.up_one(settings),
             Key::ArrowDown => self.settings_input.down_one(settings),
             Key::ArrowLeft => self.settings_input.left_one(settings),
             Key::ArrowRight => self.settings_input.right_one(settings),
             Key::KeyT => self.settings_input.toggle(settings),
-            Key::KeyX => {
-                let pos = settings.find("pos").value().clone();
-                settings.find_mut("light_pos").set_value(pos);
-            }
+            Key::KeyX => self.place_light(settings),
             Key::Backquote => {
                 if self.spaceship.is_none() {
                     self.spaceship = Some((Vector3::zero(), Vector3::zero(), Instant::now()));
@@ -129,6 +211,139 @@ impl Input {
         Ok(())
     }
 
+    // Puts the light whose setting is selected at the camera, aimed along the view. An off light
+    // is turned into a point light.
+    fn place_light(&self, settings: &mut Settings) {
+        let selected = self.settings_input.selected_key(settings);
+        let index = (0..MAX_LIGHTS)
+            .find(|&index| selected.starts_with(&light_key(index, "")))
+            .unwrap_or(0);
+        let pos = settings.find("pos").value().clone();
+        let look = settings.find("look").value().clone();
+        settings.find_mut(&light_key(index, "pos")).set_value(pos);
+        match settings.find(&light_key(index, "type")).unwrap_u32() as u32 {
+            LIGHT_OFF => settings
+                .find_mut(&light_key(index, "type"))
+                .set_value(SettingValueEnum::Int(LIGHT_POINT as u64)),
+            LIGHT_DIRECTIONAL | LIGHT_SPOT => {
+                settings.find_mut(&light_key(index, "dir")).set_value(look)
+            }
+            // only the position matters
+            LIGHT_POINT | LIGHT_SPHERE | LIGHT_CONSTANT => (),
+            _ => (),
+        }
+        info!("Light {} moved to the camera", index + 1);
+    }
+
+    // tracks.clam5 takes priority over the keyframes, like in `--video`
+    fn load_tracks() -> Option<TrackList> {
+        if !std::path::Path::new("tracks.clam5").exists() {
+            return None;
+        }
+        match TrackList::load("tracks.clam5", Settings::get_default(), false) {
+            Ok(tracks) => Some(tracks),
+            Err(err) => {
+                info!("Error loading tracks.clam5, playing keyframes: {}", err);
+                None
+            }
+        }
+    }
+
+    // Tracks get the time of the keyframes they would be converted from
+    fn video_len_secs(&self, keyframes: &KeyframeList) -> f64 {
+        let len = match &self.tracks {
+            Some(tracks) => tracks.segments() + 1,
+            None => keyframes.len(),
+        };
+        len as f64 * (10.0 / 6.0)
+    }
+
+    fn interpolate(&self, keyframes: &KeyframeList, time: f64) -> Settings {
+        match &self.tracks {
+            Some(tracks) => tracks.interpolate(time, false),
+            None => keyframes.interpolate(time, false),
+        }
+    }
+
+    fn update_timing(&mut self, keyframes: &KeyframeList) {
+        self.path_timing = match &self.tracks {
+            Some(tracks) => tracks.timing(false, self.path_speed),
+            None => keyframes.timing(false, self.path_speed),
+        };
+    }
+
+    fn keyframes_changed(
+        &mut self,
+        keyframes: &KeyframeList,
+        default_settings: &Settings,
+    ) -> Result<(), Error> {
+        self.update_timing(keyframes);
+        keyframes.save("keyframes.clam5", default_settings)
+    }
+
+    fn jump_to_keyframe(
+        &mut self,
+        index: usize,
+        settings: &mut Settings,
+        keyframes: &KeyframeList,
+    ) {
+        if let Some(keyframe) = keyframes.get(index) {
+            *settings = keyframe.clone();
+            self.keyframe_index = index;
+            self.playing = false;
+            // The keyframes aren't on the timeline of tracks.clam5
+            if self.tracks.is_none() {
+                let time = self
+                    .path_timing
+                    .unmap(keyframes.keyframe_time(index, false));
+                self.cur_video_secs = time * self.video_len_secs(keyframes);
+            }
+        }
+    }
+
+    fn scrub(&mut self, settings: &mut Settings, keyframes: &KeyframeList, now: Instant) {
+        let mut delta = 0.0;
+        if let Some(dt) = self.is_pressed(now, Key::KeyE) {
+            delta += dt;
+        }
+        if let Some(dt) = self.is_pressed(now, Key::KeyQ) {
+            delta -= dt;
+        }
+        if delta == 0.0 || (self.tracks.is_none() && keyframes.len() == 0) {
+            return;
+        }
+        let video_len_secs = self.video_len_secs(keyframes);
+        self.playing = false;
+        self.cur_video_secs = (self.cur_video_secs + delta).clamp(0.0, video_len_secs);
+        let time = self.path_timing.map(self.cur_video_secs / video_len_secs);
+        *settings = self.interpolate(keyframes, time);
+        if keyframes.len() == 0 {
+            return;
+        }
+        let position = time * (keyframes.len() - 1) as f64;
+        self.keyframe_index = (position as usize).min(keyframes.len() - 1);
+    }
+
+    pub fn status(&self, keyframes: &KeyframeList) -> String {
+        if self.tracks.is_some() {
+            return format!(
+                "tracks.clam5, {:.2}s/{:.2}s\n",
+                self.cur_video_secs,
+                self.video_len_secs(keyframes)
+            );
+        }
+        if keyframes.len() == 0 {
+            return String::new();
+        }
+        format!(
+            "keyframe {}/{}, {:.2}s/{:.2}s\n",
+            self.keyframe_index + 1,
+            keyframes.len(),
+            self.cur_video_secs,
+            self.video_len_secs(keyframes)
+        )
+    }
+
     fn run(&mut self, settings: &mut Settings, keyframes: &KeyframeList, now: Instant) {
         let dt = (now - self.last_update).as_secs_f64();
         self.last_update = now;
@@ -147,12 +362,17 @@ impl Input {
         );
         self.exp_setting(settings, now, "fov", 1.0, Key::KeyN, Key::KeyM);
         self.manual_control(settings, now);
+        self.scrub(settings, keyframes, now);
         for value in self.pressed_keys.values_mut() {
             *value = now;
         }
-        if self.cur_video_secs < self.video_len_secs {
-            *settings = keyframes.interpolate(self.cur_video_secs / self.video_len_secs, false);
+        let video_len_secs = self.video_len_secs(keyframes);
+        if self.playing && self.cur_video_secs < video_len_secs {
+            let time = self.path_timing.map(self.cur_video_secs / video_len_secs);
+            *settings = self.interpolate(keyframes, time);
             self.cur_video_secs += dt;
+        } else {
+            self.playing = false;
         }
     }
 
@@ -41,9 +41,16 @@ impl SyncInteractiveKernel {
         self.kernel.resize(device, width, height)
     }
 
-    pub fn run(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+    pub fn run(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
         self.input.integrate(&mut self.settings, &self.keyframes);
-        self.kernel.run(device, encoder, &self.settings);
+        self.kernel.run(device, queue, encoder, &self.settings, 1);
+        self.kernel.set_denoise(self.input.denoise);
+        self.kernel.denoise(device, encoder);
     }
 
     pub fn texture(&self) -> &wgpu::Buffer {
@@ -55,6 +62,10 @@ impl SyncInteractiveKernel {
     }
 
     pub fn status(&self) -> String {
-        self.input.settings_input.status(&self.settings)
+        format!(
+            "{}{}",
+            self.input.status(&self.keyframes),
+            self.input.settings_input.status(&self.settings)
+        )
     }
 }
@@ -1,8 +1,50 @@
 use crate::{
-    buffer_blit::BufferBlit, cast_slice, kernel_uniforms::KernelUniforms, settings::Settings,
+    buffer_blit::BufferBlit,
+    cast_slice,
+    denoise::Denoiser,
+    kernel_uniforms::KernelUniforms,
+    palette::Palette,
+    settings::Settings,
+    sky::{Sky, SkySource, DEFAULT_SKY_MAP, MIN_SUN_RADIUS},
     CpuTexture,
 };
-use wgpu::util::DeviceExt;
+use glam::{Vec3, Vec4};
+use instant::Instant;
+use std::sync::Arc;
+
+// Mirrors `PixelStats` in the shader
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PixelStats {
+    pub count: u32,
+    mean: f32,
+    m2: f32,
+    dummy: u32,
+}
+
+impl PixelStats {
+    // Same as `NoiseEstimate` in the shader
+    pub fn noise(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.count as f32;
+        let variance = self.m2 / (n - 1.0);
+        (variance / n).sqrt() / (self.mean + 0.1)
+    }
+}
+
+// First hit features of a pixel, averaged over its samples, see `Aov` in the shader
+pub struct PixelAov {
+    pub albedo: [f32; 3],
+    pub depth: f32,
+    pub normal: [f32; 3],
+    pub counter: f32,
+    pub steps: f32,
+}
+
+// Baked procedural skies kept besides the current one, about 2MB each
+const SKY_CACHE_SIZE: usize = 8;
 
 struct KernelImage {
     width: u32,
@@ -10,14 +52,24 @@ struct KernelImage {
     scale: u32,
     img: wgpu::Buffer,
     randbuf: wgpu::Buffer,
+    stats: wgpu::Buffer,
+    aovs: wgpu::Buffer,
     uniforms: wgpu::Buffer,
     sampler: wgpu::Sampler,
-    sky: wgpu::Texture,
+    sky: Sky,
+    sky_source: SkySource,
+    // recently used procedural skies, oldest first
+    sky_cache: Vec<(SkySource, Sky)>,
+    palette: Palette,
     bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
 }
 
-fn new_texes(device: &wgpu::Device, width: u32, height: u32) -> (wgpu::Buffer, wgpu::Buffer) {
+fn new_texes(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+) -> (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer) {
     let img = device.create_buffer(&wgpu::BufferDescriptor {
         label: None,
         size: width as u64 * height as u64 * (4 * 4),
@@ -30,80 +82,36 @@ fn new_texes(device: &wgpu::Device, width: u32, height: u32) -> (wgpu::Buffer, w
         usage: wgpu::BufferUsages::STORAGE,
         mapped_at_creation: false,
     });
-    (img, randbuf)
-}
-
-fn load_sky(device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::Texture {
-    #[cfg(target_arch = "wasm32")]
-    let file = include_bytes!("../HDR_029_Sky_Cloudy_Env.hdr") as &[u8];
-    #[cfg(not(target_arch = "wasm32"))]
-    let file = std::fs::File::open("HDR_029_Sky_Cloudy_Env.hdr").unwrap();
-    let image = hdrldr::load(file).unwrap();
-    let image_rgba: Vec<(f32, f32, f32, f32)> = image
-        .data
-        .into_iter()
-        .map(|rgb| (rgb.r, rgb.g, rgb.b, 1.0))
-        .collect();
-    let contents = cast_slice::<(f32, f32, f32, f32), u8>(&image_rgba);
-
-    let texture = device.create_texture(&wgpu::TextureDescriptor {
+    let stats = device.create_buffer(&wgpu::BufferDescriptor {
         label: None,
-        size: wgpu::Extent3d {
-            width: image.width as u32,
-            height: image.height as u32,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-        view_formats: &[],
+        size: width as u64 * height as u64 * std::mem::size_of::<PixelStats>() as u64,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_DST
+            | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
     });
-
-    let mut encoder =
-        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-
-    let image_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    // albedo and depth, normal, color counter and ray steps
+    let aovs = device.create_buffer(&wgpu::BufferDescriptor {
         label: None,
-        contents,
-        usage: wgpu::BufferUsages::COPY_SRC,
+        size: width as u64 * height as u64 * (3 * 4 * 4),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
     });
-    encoder.copy_buffer_to_texture(
-        wgpu::ImageCopyBuffer {
-            buffer: &image_buf,
-            layout: wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(image.width as u32 * 16),
-                rows_per_image: Some(image.height as u32),
-            },
-        },
-        wgpu::ImageCopyTexture {
-            texture: &texture,
-            mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
-            aspect: wgpu::TextureAspect::All,
-        },
-        wgpu::Extent3d {
-            width: image.width as u32,
-            height: image.height as u32,
-            depth_or_array_layers: 1,
-        },
-    );
-
-    queue.submit(std::iter::once(encoder.finish()));
-
-    texture
+    (img, randbuf, stats, aovs)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_bind_group(
     device: &wgpu::Device,
     bind_group_layout: &wgpu::BindGroupLayout,
     img: &wgpu::Buffer,
     randbuf: &wgpu::Buffer,
+    stats: &wgpu::Buffer,
+    aovs: &wgpu::Buffer,
     uniforms: &wgpu::Buffer,
     sampler: &wgpu::Sampler,
-    sky: &wgpu::Texture,
+    sky: &Sky,
+    palette: &Palette,
 ) -> wgpu::BindGroup {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: None,
@@ -140,7 +148,40 @@ fn create_bind_group(
             wgpu::BindGroupEntry {
                 binding: 4,
                 resource: wgpu::BindingResource::TextureView(
-                    &sky.create_view(&wgpu::TextureViewDescriptor::default()),
+                    &sky.texture
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: stats,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: aovs,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &sky.distribution,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: wgpu::BindingResource::TextureView(
+                    &palette
+                        .texture
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
                 ),
             },
         ],
@@ -193,34 +234,83 @@ impl KernelImage {
                     visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Texture {
                         view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        view_dimension: wgpu::TextureViewDimension::D1,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         multisampled: false,
                     },
                     count: None,
                 },
             ],
         });
-        let (img, randbuf) = new_texes(device, width, height);
+        let (img, randbuf, stats, aovs) = new_texes(device, width, height);
         let uniforms = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
             size: std::mem::size_of::<KernelUniforms>() as u64,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
+        // wraps around horizontally, the poles are clamped
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
             min_filter: wgpu::FilterMode::Linear,
             mag_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
             ..Default::default()
         });
-        let sky = load_sky(device, queue);
+        let sky_source = SkySource::Map(DEFAULT_SKY_MAP.to_string());
+        let sky = Sky::load(device, queue, &sky_source);
+        let palette = Palette::new(device);
         let bind_group = create_bind_group(
             device,
             &bind_group_layout,
             &img,
             &randbuf,
+            &stats,
+            &aovs,
             &uniforms,
             &sampler,
             &sky,
+            &palette,
         );
         Self {
             width,
@@ -228,14 +318,57 @@ impl KernelImage {
             scale: 1,
             img,
             randbuf,
+            stats,
+            aovs,
             uniforms,
             sampler,
             sky,
+            sky_source,
+            sky_cache: Vec::new(),
+            palette,
             bind_group_layout,
             bind_group,
         }
     }
 
+    // Reloads or rebakes the sky when its source changed. Procedural skies are baked from
+    // rounded parameters and the last few bakes are kept, so animating or tweaking the sun
+    // mostly reuses them.
+    fn set_sky(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, source: &SkySource) {
+        let source = source.bake_key();
+        if self.sky_source == source {
+            return;
+        }
+        let sky = match self
+            .sky_cache
+            .iter()
+            .position(|(cached, _)| *cached == source)
+        {
+            Some(index) => self.sky_cache.remove(index).1,
+            None => Sky::load(device, queue, &source),
+        };
+        let old_source = std::mem::replace(&mut self.sky_source, source);
+        let old_sky = std::mem::replace(&mut self.sky, sky);
+        if let SkySource::Procedural(_) = old_source {
+            self.sky_cache.push((old_source, old_sky));
+            if self.sky_cache.len() > SKY_CACHE_SIZE {
+                self.sky_cache.remove(0);
+            }
+        }
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.img,
+            &self.randbuf,
+            &self.stats,
+            &self.aovs,
+            &self.uniforms,
+            &self.sampler,
+            &self.sky,
+            &self.palette,
+        );
+    }
+
     fn size(&self) -> (u32, u32) {
         (self.width / self.scale, self.height / self.scale)
     }
@@ -253,17 +386,22 @@ impl KernelImage {
         self.scale = new_scale.max(1);
         let (width, height) = self.size();
         if old_size != (width, height) {
-            let (img, randbuf) = new_texes(device, width, height);
+            let (img, randbuf, stats, aovs) = new_texes(device, width, height);
             self.img = img;
             self.randbuf = randbuf;
+            self.stats = stats;
+            self.aovs = aovs;
             self.bind_group = create_bind_group(
                 device,
                 &self.bind_group_layout,
                 &self.img,
                 &self.randbuf,
+                &self.stats,
+                &self.aovs,
                 &self.uniforms,
                 &self.sampler,
                 &self.sky,
+                &self.palette,
             );
             true
         } else {
@@ -272,11 +410,119 @@ impl KernelImage {
     }
 }
 
+// Rows of a texture to buffer copy have to be aligned
+fn padded_bytes_per_row(width: u32) -> u32 {
+    (width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+}
+
+const READBACK_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+// Resources to get the image back to the cpu, kept around between frames for video rendering.
+// Pending downloads share their staging buffers, so a resize can replace this while one is open.
+struct Readback {
+    texture: wgpu::Texture,
+    blit: BufferBlit,
+    buffers: [Arc<wgpu::Buffer>; 2],
+    // same slots as `buffers`, created when the AOVs are downloaded with the image
+    aov_buffers: Option<[Arc<wgpu::Buffer>; 2]>,
+    next: usize,
+}
+
+impl Readback {
+    fn new(device: &wgpu::Device, src: &wgpu::Buffer, size: (u32, u32)) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: READBACK_FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let blit = BufferBlit::new(device, READBACK_FORMAT, src, size, false);
+        let buffer = || {
+            Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: padded_bytes_per_row(size.0) as u64 * size.1 as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }))
+        };
+        Self {
+            texture,
+            blit,
+            buffers: [buffer(), buffer()],
+            aov_buffers: None,
+            next: 0,
+        }
+    }
+}
+
+// How the samples of an image are split into dispatches
+#[derive(Clone, Copy)]
+pub struct SampleBatching {
+    pub samples_per_dispatch: u32,
+    // Maximum duration of one dispatch in seconds. Dispatches are then submitted and waited on one
+    // at a time, for platforms where a gpu watchdog kills long running work.
+    pub time_budget: Option<f64>,
+}
+
+impl Default for SampleBatching {
+    fn default() -> Self {
+        Self {
+            samples_per_dispatch: 16,
+            // Windows resets the driver when a dispatch takes longer than 2 seconds (TDR)
+            time_budget: if cfg!(windows) { Some(0.5) } else { None },
+        }
+    }
+}
+
+fn parse_aovs(data: &[u8]) -> Vec<PixelAov> {
+    data.chunks_exact(3 * 4 * 4)
+        .map(|chunk| {
+            let float =
+                |i: usize| f32::from_le_bytes([chunk[i], chunk[i + 1], chunk[i + 2], chunk[i + 3]]);
+            PixelAov {
+                albedo: [float(0), float(4), float(8)],
+                depth: float(12),
+                normal: [float(16), float(20), float(24)],
+                counter: float(32),
+                steps: float(36),
+            }
+        })
+        .collect()
+}
+
+pub struct PendingDownload {
+    buffer: Arc<wgpu::Buffer>,
+    aov_buffer: Option<Arc<wgpu::Buffer>>,
+    submission: wgpu::SubmissionIndex,
+    mapped: std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>,
+    aovs_mapped: Option<std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>>,
+    size: (u32, u32),
+}
+
 pub struct Kernel {
     kernel: wgpu::ComputePipeline,
     data: KernelImage,
     old_settings: Settings,
-    frame: u32,
+    samples_per_dispatch: u32,
+    noise_threshold: f32,
+    min_samples: u32,
+    transparent: bool,
+    uniforms_dirty: bool,
+    // counts accumulation restarts, so every image gets new random numbers
+    seed: u32,
+    readback: Option<Readback>,
+    download_aovs: bool,
+    // created on first use
+    denoiser: Option<Denoiser>,
+    denoise: bool,
 }
 
 impl Kernel {
@@ -300,54 +546,85 @@ impl Kernel {
             kernel: pipeline,
             data,
             old_settings: Settings::new(),
-            frame: 0,
+            samples_per_dispatch: 0,
+            noise_threshold: 0.0,
+            min_samples: 0,
+            transparent: false,
+            uniforms_dirty: true,
+            seed: 0,
+            readback: None,
+            download_aovs: false,
+            denoiser: None,
+            denoise: false,
         }
     }
 
     pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
         if self.data.resize(device, width, height, self.data.scale) {
-            self.frame = 0;
+            self.readback = None;
         }
     }
 
+    // Records one dispatch that accumulates `samples_per_dispatch` samples per pixel. The
+    // uniforms are written with `queue.write_buffer`, which lands at the next submit, so the
+    // settings and sample count must not change between runs recorded into the same encoder.
     pub fn run(
         &mut self,
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         encoder: &mut wgpu::CommandEncoder,
         settings: &Settings,
+        samples_per_dispatch: u32,
     ) {
-        if self.data.resize(
+        let resized = self.data.resize(
             device,
             self.data.width,
             self.data.height,
             settings.find("render_scale").unwrap_u32() as u32,
-        ) || &self.old_settings != settings
+        );
+        if resized {
+            self.readback = None;
+        }
+        let sky_source = SkySource::from_settings(settings);
+        self.data.set_sky(device, queue, &sky_source);
+        self.data
+            .palette
+            .set(queue, settings.find("palette").unwrap_text());
+        let settings_changed = &self.old_settings != settings;
+        if settings_changed {
+            // restart accumulation
+            encoder.clear_buffer(&self.data.stats, 0, None);
+        }
+        if resized || settings_changed {
+            self.seed = self.seed.wrapping_add(1);
+        }
+        if resized
+            || settings_changed
+            || self.uniforms_dirty
+            || self.samples_per_dispatch != samples_per_dispatch
         {
-            self.frame = 0;
+            let mut uniforms = KernelUniforms::from_settings(settings);
+            let (width, height) = self.data.size();
+            uniforms.width = width;
+            uniforms.height = height;
+            uniforms.samples_per_dispatch = samples_per_dispatch;
+            uniforms.noise_threshold = self.noise_threshold;
+            uniforms.min_samples = self.min_samples;
+            uniforms.transparent = u32::from(self.transparent);
+            uniforms.seed = self.seed;
+            let (sun_direction, sun_color) = sky_source.sun();
+            let sun_radius = settings
+                .find("sun_radius")
+                .unwrap_float()
+                .max(MIN_SUN_RADIUS);
+            uniforms.sun_direction = Vec4::from((Vec3::from(sun_direction), sun_radius as f32));
+            uniforms.sun_color = Vec4::from((Vec3::from(sun_color), 0.0));
+            let uniforms_arr = [uniforms];
+            queue.write_buffer(&self.data.uniforms, 0, cast_slice(&uniforms_arr));
+            self.old_settings = settings.clone();
+            self.samples_per_dispatch = samples_per_dispatch;
+            self.uniforms_dirty = false;
         }
-        let mut uniforms = KernelUniforms::from_settings(settings);
-        let (width, height) = self.data.size();
-        uniforms.width = width;
-        uniforms.height = height;
-        uniforms.frame = self.frame;
-        let uniforms_arr = [uniforms];
-        let uniforms_u8: &[u8] = cast_slice(&uniforms_arr);
-
-        let uniforms_staging = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: uniforms_u8,
-            usage: wgpu::BufferUsages::COPY_SRC,
-        });
-        encoder.copy_buffer_to_buffer(
-            &uniforms_staging,
-            0,
-            &self.data.uniforms,
-            0,
-            std::mem::size_of::<KernelUniforms>() as u64,
-        );
-
-        // queue.write_buffer(&self.data.uniforms, 0, uniforms_u8);
-        self.old_settings = settings.clone();
 
         let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
             label: None,
@@ -356,79 +633,270 @@ impl Kernel {
         pass.set_pipeline(&self.kernel);
         pass.set_bind_group(0, &self.data.bind_group, &[]);
         let (width, height) = self.data.size();
-        let mut num_workgroups_x = (width * height + 63) / 64;
+        let mut num_workgroups_x = (width * height).div_ceil(64);
         let mut num_workgroups_y = 1;
         // TODO: hardcoded 2^16
         while num_workgroups_x > (1 << 16) {
-            num_workgroups_x = (num_workgroups_x + 1) / 2;
+            num_workgroups_x = num_workgroups_x.div_ceil(2);
             num_workgroups_y *= 2;
         }
         pass.dispatch_workgroups(num_workgroups_x, num_workgroups_y, 1);
-        self.frame += 1;
+    }
+
+    // Accumulates `samples` samples per pixel, split into dispatches according to `batching`
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        settings: &Settings,
+        samples: usize,
+        batching: SampleBatching,
+    ) {
+        let mut per_dispatch = batching.samples_per_dispatch.max(1) as usize;
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let mut recorded = false;
+        let mut done = 0;
+        while done < samples {
+            let count = per_dispatch.min(samples - done);
+            if recorded && count as u32 != self.samples_per_dispatch {
+                queue.submit(std::iter::once(encoder.finish()));
+                encoder =
+                    device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            }
+            self.run(device, queue, &mut encoder, settings, count as u32);
+            recorded = true;
+            done += count;
+            if let Some(budget) = batching.time_budget {
+                let start = Instant::now();
+                queue.submit(std::iter::once(encoder.finish()));
+                encoder =
+                    device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+                recorded = false;
+                device.poll(wgpu::Maintain::Wait);
+                let elapsed = start.elapsed().as_secs_f64().max(1e-6);
+                // aim below the budget, and grow slowly in case the first dispatches were cheap
+                let fit = (count as f64 * budget * 0.75 / elapsed) as usize;
+                per_dispatch = fit.clamp(1, count * 2);
+            }
+        }
+        self.denoise(device, &mut encoder);
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // When enabled, `texture` and downloads return the denoised image instead of the raw one
+    pub fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise;
+    }
+
+    // Records the denoiser over the current image, does nothing when denoising is disabled
+    pub fn denoise(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        if !self.denoise {
+            return;
+        }
+        let size = self.data.size();
+        self.denoiser
+            .get_or_insert_with(|| Denoiser::new(device))
+            .run(
+                device,
+                encoder,
+                &self.data.img,
+                &self.data.aovs,
+                &self.data.stats,
+                size,
+            );
+    }
+
+    // Pixels with at least `min_samples` samples and an estimated noise below `noise_threshold`
+    // stop receiving samples, 0 disables adaptive sampling
+    pub fn set_adaptive(&mut self, noise_threshold: f32, min_samples: u32) {
+        self.noise_threshold = noise_threshold;
+        self.min_samples = min_samples;
+        self.uniforms_dirty = true;
+    }
+
+    // Camera rays that escape to the sky leave the pixel transparent, and downloads keep alpha
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.transparent = transparent;
+        self.uniforms_dirty = true;
+    }
+
+    pub fn download_stats(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<PixelStats> {
+        let data = Self::download_buffer(device, queue, &self.data.stats);
+        data.chunks_exact(std::mem::size_of::<PixelStats>())
+            .map(|chunk| {
+                let word = |i: usize| [chunk[i], chunk[i + 1], chunk[i + 2], chunk[i + 3]];
+                PixelStats {
+                    count: u32::from_le_bytes(word(0)),
+                    mean: f32::from_le_bytes(word(4)),
+                    m2: f32::from_le_bytes(word(8)),
+                    dummy: 0,
+                }
+            })
+            .collect()
+    }
+
+    pub fn download_aovs(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<PixelAov> {
+        parse_aovs(&Self::download_buffer(device, queue, &self.data.aovs))
+    }
+
+    // Also reads back the AOVs with every `start_download`, for `finish_download_with_aovs`
+    pub fn set_download_aovs(&mut self, download_aovs: bool) {
+        self.download_aovs = download_aovs;
+    }
+
+    fn download_buffer(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        buffer: &wgpu::Buffer,
+    ) -> Vec<u8> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        wgpu::util::DownloadBuffer::read_buffer(device, queue, &buffer.slice(..), move |dl| {
+            tx.send(dl.unwrap().to_vec()).unwrap()
+        });
+        device.poll(wgpu::Maintain::Wait);
+        rx.recv().unwrap()
     }
 
     pub fn texture(&self) -> &wgpu::Buffer {
-        &self.data.img
+        match &self.denoiser {
+            Some(denoiser) if self.denoise => denoiser.output().unwrap_or(&self.data.img),
+            _ => &self.data.img,
+        }
     }
 
     pub fn texture_size(&self) -> (u32, u32) {
         self.data.size()
     }
 
-    pub fn download(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> CpuTexture {
+    pub fn download(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> CpuTexture {
+        let pending = self.start_download(device, queue);
+        self.finish_download(device, pending)
+    }
+
+    // Queues a copy of the current image into a staging buffer and returns without waiting, so
+    // the next frame can be submitted before the copy is read with `finish_download`. Two
+    // staging buffers alternate, so at most one download may be pending when starting another.
+    pub fn start_download(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> PendingDownload {
         let size = self.texture_size();
+        if self.readback.is_none() {
+            self.readback = Some(Readback::new(device, &self.data.img, size));
+        }
+        let src = match &self.denoiser {
+            Some(denoiser) if self.denoise => denoiser.output().unwrap_or(&self.data.img),
+            _ => &self.data.img,
+        };
+        let readback = self.readback.as_mut().unwrap();
+        readback.blit.set_src(device, src, size, None);
+        let slot = readback.next;
+        readback.next = (slot + 1) % readback.buffers.len();
+        let buffer = readback.buffers[slot].clone();
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        let tex = Self::copy_buffer_to_texture(device, &mut encoder, &self.data.img, size);
-        let buf = Self::copy_texture_to_buffer(device, &mut encoder, &tex, size);
-        queue.submit(std::iter::once(encoder.finish()));
-        let data = Self::download_buffer(device, queue, &buf);
-        let mut result = CpuTexture {
-            data,
-            size: (self.data.width, self.data.height),
+        readback.blit.blit(
+            device,
+            &mut encoder,
+            &readback.texture.create_view(&Default::default()),
+        );
+        Self::copy_texture_to_buffer(&mut encoder, &readback.texture, &buffer, size);
+        let aovs = &self.data.aovs;
+        let aov_buffer = if self.download_aovs {
+            let aov_buffers = readback.aov_buffers.get_or_insert_with(|| {
+                [(); 2].map(|_| {
+                    Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+                        label: None,
+                        size: aovs.size(),
+                        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                        mapped_at_creation: false,
+                    }))
+                })
+            });
+            encoder.copy_buffer_to_buffer(aovs, 0, &aov_buffers[slot], 0, aovs.size());
+            Some(aov_buffers[slot].clone())
+        } else {
+            None
+        };
+        let submission = queue.submit(std::iter::once(encoder.finish()));
+        let map = |buffer: &wgpu::Buffer| {
+            let (tx, rx) = std::sync::mpsc::sync_channel(1);
+            buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
+            rx
         };
-        result.rgba_to_rgb();
-        result
+        PendingDownload {
+            mapped: map(&buffer),
+            aovs_mapped: aov_buffer.as_deref().map(map),
+            buffer,
+            aov_buffer,
+            submission,
+            size,
+        }
     }
 
-    fn copy_buffer_to_texture(
+    pub fn finish_download(
+        &mut self,
         device: &wgpu::Device,
-        encoder: &mut wgpu::CommandEncoder,
-        src: &wgpu::Buffer,
-        size: (u32, u32),
-    ) -> wgpu::Texture {
-        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
-        let dst = device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
-            size: wgpu::Extent3d {
-                width: size.0,
-                height: size.1,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format,
-            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            view_formats: &[],
+        pending: PendingDownload,
+    ) -> CpuTexture {
+        self.finish_download_with_aovs(device, pending).0
+    }
+
+    // The AOVs are there when `set_download_aovs` was on for `start_download`
+    pub fn finish_download_with_aovs(
+        &mut self,
+        device: &wgpu::Device,
+        pending: PendingDownload,
+    ) -> (CpuTexture, Option<Vec<PixelAov>>) {
+        device.poll(wgpu::Maintain::WaitForSubmissionIndex(pending.submission));
+        pending
+            .mapped
+            .recv()
+            .unwrap()
+            .expect("Couldn't map readback buffer");
+        let buffer = &pending.buffer;
+        let (width, height) = pending.size;
+        let padded_row = padded_bytes_per_row(width) as usize;
+        let alpha = self.transparent;
+        let channels = if alpha { 4 } else { 3 };
+        let mut data = Vec::with_capacity(width as usize * height as usize * channels);
+        {
+            let mapped = buffer.slice(..).get_mapped_range();
+            for row in mapped.chunks_exact(padded_row) {
+                for rgba in row[..width as usize * 4].chunks_exact(4) {
+                    data.extend_from_slice(&rgba[..channels]);
+                }
+            }
+        }
+        buffer.unmap();
+        let aovs = pending.aovs_mapped.map(|mapped| {
+            mapped
+                .recv()
+                .unwrap()
+                .expect("Couldn't map readback buffer");
+            let buffer = pending.aov_buffer.as_ref().unwrap();
+            let aovs = parse_aovs(&buffer.slice(..).get_mapped_range());
+            buffer.unmap();
+            aovs
         });
-        let mut texture_blit = BufferBlit::new(device, format, src, size, false);
-        texture_blit.blit(device, encoder, &dst.create_view(&Default::default()));
-        dst
+        let image = CpuTexture {
+            data,
+            size: pending.size,
+            alpha,
+        };
+        (image, aovs)
     }
 
     fn copy_texture_to_buffer(
-        device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         src: &wgpu::Texture,
+        dst: &wgpu::Buffer,
         size: (u32, u32),
-    ) -> wgpu::Buffer {
-        let dst = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: size.0 as u64 * size.1 as u64 * 4,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
+    ) {
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 texture: src,
@@ -437,10 +905,10 @@ impl Kernel {
                 aspect: wgpu::TextureAspect::All,
             },
             wgpu::ImageCopyBuffer {
-                buffer: &dst,
+                buffer: dst,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(size.0 * 4),
+                    bytes_per_row: Some(padded_bytes_per_row(size.0)),
                     rows_per_image: Some(size.1),
                 },
             },
@@ -450,19 +918,5 @@ impl Kernel {
                 depth_or_array_layers: 1,
             },
         );
-        dst
-    }
-
-    fn download_buffer(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        buffer: &wgpu::Buffer,
-    ) -> Vec<u8> {
-        let (tx, rx) = std::sync::mpsc::sync_channel(1);
-        wgpu::util::DownloadBuffer::read_buffer(device, queue, &buffer.slice(..), move |dl| {
-            tx.send(dl.unwrap().to_vec()).unwrap()
-        });
-        device.poll(wgpu::Maintain::Wait);
-        rx.recv().unwrap()
     }
 }
@@ -5,6 +5,42 @@ use crate::{
 use cgmath::Vector3;
 use glam::Vec4;
 
+pub const MAX_LIGHTS: usize = 4;
+
+// Values of `light{n}_type`
+pub const LIGHT_OFF: u32 = 0;
+pub const LIGHT_POINT: u32 = 1;
+pub const LIGHT_SPHERE: u32 = 2;
+pub const LIGHT_DIRECTIONAL: u32 = 3;
+pub const LIGHT_SPOT: u32 = 4;
+pub const LIGHT_CONSTANT: u32 = 5;
+
+// Intensity of the first light, a constant light. Close to how bright the single light was
+// before there were types, for a white surface facing it from the default distance.
+const CONSTANT_LIGHT_INTENSITY: f64 = 16.0;
+
+// Values of `projection`
+pub const PROJECTION_EQUIRECTANGULAR: u32 = 1;
+pub const PROJECTION_FISHEYE: u32 = 2;
+pub const PROJECTION_CUBEMAP: u32 = 3;
+
+// Values of `stereo`
+pub const STEREO_SIDE_BY_SIDE: u32 = 1;
+pub const STEREO_OVER_UNDER: u32 = 2;
+
+// Mirrors `Light` in the shader
+#[repr(C)]
+#[derive(Default)]
+pub struct Light {
+    pos: Vec4,
+    dir: Vec4,
+    color: Vec4,
+    intensity: f32,
+    radius: f32,
+    angle: f32,
+    kind: u32,
+}
+
 #[repr(C)]
 #[derive(Default)]
 pub struct KernelUniforms {
@@ -23,14 +59,29 @@ pub struct KernelUniforms {
     fog_distance: f32,
     fog_brightness: f32,
     sky_brightness: f32,
+    sky_rotation: f32,
     surface_color_variance: f32,
     surface_color_shift: f32,
     surface_color_saturation: f32,
     surface_color_value: f32,
     surface_color_gloss: f32,
+    surface_roughness: f32,
+    surface_metallic: f32,
+    surface_roughness_variance: f32,
+    surface_metallic_variance: f32,
+    surface_transmission: f32,
+    surface_ior: f32,
+    absorption_distance: f32,
     plane: Vec4,
-    light_pos: Vec4,
-    light_color: Vec4,
+    lights: [Light; MAX_LIGHTS],
+    sky_tint: Vec4,
+    // towards the sun with its angular radius in w, see `SkySource::sun`
+    pub sun_direction: Vec4,
+    // irradiance of the sun, zero without one
+    pub sun_color: Vec4,
+    emission_color: Vec4,
+    trap_point: Vec4,
+    absorption_color: Vec4,
     rotation: f32,
     bailout: f32,
     bailout_normal: f32,
@@ -47,35 +98,49 @@ pub struct KernelUniforms {
     max_ray_steps: u32,
     num_ray_bounces: u32,
     gamma_test: u32,
+    emission_mode: u32,
+    emission_intensity: f32,
+    emission_threshold: f32,
+    color_mode: u32,
+    palette_scale: f32,
+    palette_offset: f32,
+    trap_shape: u32,
+    trap_radius: f32,
+    projection: u32,
+    fisheye_angle: f32,
+    equirectangular_angle: f32,
+    stereo: u32,
+    interpupillary_distance: f32,
+    convergence: f32,
     pub width: u32,
     pub height: u32,
-    pub frame: u32,
+    pub samples_per_dispatch: u32,
+    pub noise_threshold: f32,
+    pub min_samples: u32,
+    pub transparent: u32,
+    pub seed: u32,
 }
 
-enum Meta {
-    Int(
-        &'static str,
-        u64,
-        fn(&KernelUniforms) -> &u32,
-        fn(&mut KernelUniforms) -> &mut u32,
-    ),
+#[allow(dead_code)]
+enum Meta<T: 'static> {
+    Int(&'static str, u64, fn(&T) -> &u32, fn(&mut T) -> &mut u32),
     Float(
         &'static str,
         f64,
         f64,
-        fn(&KernelUniforms) -> &f32,
-        fn(&mut KernelUniforms) -> &mut f32,
+        fn(&T) -> &f32,
+        fn(&mut T) -> &mut f32,
     ),
     Vec3(
         &'static str,
         Vector3<f64>,
         f64,
-        fn(&KernelUniforms) -> &Vec4,
-        fn(&mut KernelUniforms) -> &mut Vec4,
+        fn(&T) -> &Vec4,
+        fn(&mut T) -> &mut Vec4,
     ),
 }
 
-const UNIFORM_METADATA: &[Meta] = &[
+const UNIFORM_METADATA: &[Meta<KernelUniforms>] = &[
     Meta::Vec3(
         "pos",
         Vector3::new(0.0, 0.0, 5.0),
@@ -97,7 +162,46 @@ const UNIFORM_METADATA: &[Meta] = &[
         |s| &s.up,
         |s| &mut s.up,
     ),
+    // 0: perspective, 1: equirectangular, 360x180 by default, 2: equidistant fisheye, 3: cubemap
+    // faces in a 3x2 grid, right left up on top and down front back below, 4: orthographic,
+    // framing what perspective shows at focal_distance
+    Meta::Int("projection", 0, |s| &s.projection, |s| &mut s.projection),
     Meta::Float("fov", 1.0, -1.0, |s| &s.fov, |s| &mut s.fov),
+    // full angle across the fisheye's circle, in radians
+    Meta::Float(
+        "fisheye_angle",
+        std::f64::consts::PI,
+        0.125,
+        |s| &s.fisheye_angle,
+        |s| &mut s.fisheye_angle,
+    ),
+    // horizontal angle the equirectangular image covers, in radians, pi for VR180
+    Meta::Float(
+        "equirectangular_angle",
+        std::f64::consts::TAU,
+        0.125,
+        |s| &s.equirectangular_angle,
+        |s| &mut s.equirectangular_angle,
+    ),
+    // 0: off, 1: left and right eye side by side, 2: left eye over the right, 3: red-cyan anaglyph
+    Meta::Int("stereo", 0, |s| &s.stereo, |s| &mut s.stereo),
+    // the eyes are offset by half of it each along the camera's right, or each ray's own
+    // horizontal right for the wide projections
+    Meta::Float(
+        "interpupillary_distance",
+        0.1,
+        -0.5,
+        |s| &s.interpupillary_distance,
+        |s| &mut s.interpupillary_distance,
+    ),
+    // distance where the eyes' views meet, nearer objects come out of the screen
+    Meta::Float(
+        "convergence",
+        3.0,
+        -1.0,
+        |s| &s.convergence,
+        |s| &mut s.convergence,
+    ),
     Meta::Float(
         "focal_distance",
         3.0,
@@ -169,6 +273,21 @@ const UNIFORM_METADATA: &[Meta] = &[
         |s| &s.sky_brightness,
         |s| &mut s.sky_brightness,
     ),
+    // radians around the y axis
+    Meta::Float(
+        "sky_rotation",
+        0.0,
+        0.125,
+        |s| &s.sky_rotation,
+        |s| &mut s.sky_rotation,
+    ),
+    Meta::Vec3(
+        "sky_tint",
+        Vector3::new(1.0, 1.0, 1.0),
+        -0.5,
+        |s| &s.sky_tint,
+        |s| &mut s.sky_tint,
+    ),
     Meta::Float(
         "surface_color_variance",
         0.0625,
@@ -197,6 +316,8 @@ const UNIFORM_METADATA: &[Meta] = &[
         |s| &s.surface_color_value,
         |s| &mut s.surface_color_value,
     ),
+    // specular reflectance of non-metals facing the camera, Fresnel raises it towards grazing
+    // angles
     Meta::Float(
         "surface_color_gloss",
         0.0,
@@ -204,26 +325,138 @@ const UNIFORM_METADATA: &[Meta] = &[
         |s| &s.surface_color_gloss,
         |s| &mut s.surface_color_gloss,
     ),
+    // GGX roughness of the specular lobe, 0 is a mirror
+    Meta::Float(
+        "surface_roughness",
+        0.0,
+        0.125,
+        |s| &s.surface_roughness,
+        |s| &mut s.surface_roughness,
+    ),
+    // metals tint their reflection with the surface color and have no diffuse part
+    Meta::Float(
+        "surface_metallic",
+        0.0,
+        0.125,
+        |s| &s.surface_metallic,
+        |s| &mut s.surface_metallic,
+    ),
+    // per color counter region offsets of roughness and metallic, up to plus or minus this
+    Meta::Float(
+        "surface_roughness_variance",
+        0.0,
+        0.125,
+        |s| &s.surface_roughness_variance,
+        |s| &mut s.surface_roughness_variance,
+    ),
+    Meta::Float(
+        "surface_metallic_variance",
+        0.0,
+        0.125,
+        |s| &s.surface_metallic_variance,
+        |s| &mut s.surface_metallic_variance,
+    ),
+    // share of the non-metallic, non-reflected light that refracts into the fractal instead of
+    // scattering diffusely
+    Meta::Float(
+        "surface_transmission",
+        0.0,
+        0.125,
+        |s| &s.surface_transmission,
+        |s| &mut s.surface_transmission,
+    ),
+    Meta::Float(
+        "surface_ior",
+        1.5,
+        0.125,
+        |s| &s.surface_ior,
+        |s| &mut s.surface_ior,
+    ),
+    // color left of white light after travelling absorption_distance inside
     Meta::Vec3(
-        "plane",
-        Vector3::new(3.0, 3.5, 2.5),
+        "absorption_color",
+        Vector3::new(1.0, 1.0, 1.0),
+        -0.25,
+        |s| &s.absorption_color,
+        |s| &mut s.absorption_color,
+    ),
+    Meta::Float(
+        "absorption_distance",
         1.0,
-        |s| &s.plane,
-        |s| &mut s.plane,
+        -0.5,
+        |s| &s.absorption_distance,
+        |s| &mut s.absorption_distance,
+    ),
+    // 0: hue from the fold counter, or through `palette`: 1: orbit trap distance, 2: smooth
+    // iteration count, 3: folds per iteration
+    Meta::Int("color_mode", 0, |s| &s.color_mode, |s| &mut s.color_mode),
+    // the palette repeats, value * palette_scale + palette_offset picks the color
+    Meta::Float(
+        "palette_scale",
+        1.0,
+        -0.25,
+        |s| &s.palette_scale,
+        |s| &mut s.palette_scale,
+    ),
+    Meta::Float(
+        "palette_offset",
+        0.0,
+        0.125,
+        |s| &s.palette_offset,
+        |s| &mut s.palette_offset,
+    ),
+    // 0: off, 1: where the color counter reaches emission_threshold, 2: where the orbit comes
+    // closer than emission_threshold to the orbit trap
+    Meta::Int(
+        "emission_mode",
+        0,
+        |s| &s.emission_mode,
+        |s| &mut s.emission_mode,
     ),
     Meta::Vec3(
-        "light_pos",
-        Vector3::new(3.0, 3.5, 2.5),
+        "emission_color",
+        Vector3::new(1.0, 0.5, 0.2),
+        -0.5,
+        |s| &s.emission_color,
+        |s| &mut s.emission_color,
+    ),
+    Meta::Float(
+        "emission_intensity",
+        4.0,
+        -0.5,
+        |s| &s.emission_intensity,
+        |s| &mut s.emission_intensity,
+    ),
+    Meta::Float(
+        "emission_threshold",
         0.25,
-        |s| &s.light_pos,
-        |s| &mut s.light_pos,
+        -0.25,
+        |s| &s.emission_threshold,
+        |s| &mut s.emission_threshold,
     ),
     Meta::Vec3(
-        "light_color",
-        Vector3::new(1.0, 1.0, 1.0),
-        -0.5,
-        |s| &s.light_color,
-        |s| &mut s.light_color,
+        "trap_point",
+        Vector3::new(0.0, 0.0, 0.0),
+        0.25,
+        |s| &s.trap_point,
+        |s| &mut s.trap_point,
+    ),
+    // shape the orbit trap measures the distance to, 0: trap_point, 1: `plane`, 2: sphere of
+    // trap_radius around trap_point
+    Meta::Int("trap_shape", 0, |s| &s.trap_shape, |s| &mut s.trap_shape),
+    Meta::Float(
+        "trap_radius",
+        1.0,
+        -0.25,
+        |s| &s.trap_radius,
+        |s| &mut s.trap_radius,
+    ),
+    Meta::Vec3(
+        "plane",
+        Vector3::new(3.0, 3.5, 2.5),
+        1.0,
+        |s| &s.plane,
+        |s| &mut s.plane,
     ),
     Meta::Float("rotation", 0.0, 0.125, |s| &s.rotation, |s| &mut s.rotation),
     Meta::Float("bailout", 64.0, -0.25, |s| &s.bailout, |s| &mut s.bailout),
@@ -295,21 +528,123 @@ const UNIFORM_METADATA: &[Meta] = &[
     Meta::Int("gamma_test", 0, |s| &s.gamma_test, |s| &mut s.gamma_test),
 ];
 
+// Settings of each light, named `light{n}_{name}` with n counting from 1. All lights default to
+// off except the first, a constant light like the single light of older scenes.
+const LIGHT_METADATA: &[Meta<Light>] = &[
+    // 0: off, 1: point, 2: sphere, 3: directional, 4: spot, 5: constant, a point light without
+    // falloff
+    Meta::Int("type", 0, |s| &s.kind, |s| &mut s.kind),
+    Meta::Vec3(
+        "pos",
+        Vector3::new(3.0, 3.5, 2.5),
+        0.25,
+        |s| &s.pos,
+        |s| &mut s.pos,
+    ),
+    // where directional and spot lights shine towards
+    Meta::Vec3(
+        "dir",
+        Vector3::new(-0.5, -0.7, -0.5),
+        0.25,
+        |s| &s.dir,
+        |s| &mut s.dir,
+    ),
+    Meta::Vec3(
+        "color",
+        Vector3::new(1.0, 1.0, 1.0),
+        -0.5,
+        |s| &s.color,
+        |s| &mut s.color,
+    ),
+    // radiant intensity with inverse square falloff, irradiance for directional and constant
+    // lights
+    Meta::Float(
+        "intensity",
+        64.0,
+        -0.5,
+        |s| &s.intensity,
+        |s| &mut s.intensity,
+    ),
+    // of sphere lights, or the angular radius of directional lights, for soft shadows
+    Meta::Float("radius", 0.0, 0.125, |s| &s.radius, |s| &mut s.radius),
+    // half angle of the spot's cone, in radians
+    Meta::Float("angle", 0.5, 0.125, |s| &s.angle, |s| &mut s.angle),
+];
+
+pub fn light_key(index: usize, name: &str) -> String {
+    format!("light{}_{}", index + 1, name)
+}
+
+fn read_meta<T>(meta: &Meta<T>, key: &str, settings: &Settings, target: &mut T) {
+    match meta {
+        Meta::Int(_, _, _, get_mut) => {
+            *get_mut(target) = settings.get(key).unwrap().unwrap_u32() as u32;
+        }
+        Meta::Float(_, _, _, _, get_mut) => {
+            *get_mut(target) = settings.get(key).unwrap().unwrap_float() as f32;
+        }
+        Meta::Vec3(_, _, _, _, get_mut) => {
+            let v = settings.get(key).unwrap().unwrap_vec3();
+            *get_mut(target) = Vec4::new(v.x as f32, v.y as f32, v.z as f32, 0.0);
+        }
+    }
+}
+
+fn default_setting<T>(meta: &Meta<T>) -> (&'static str, SettingValueEnum) {
+    match *meta {
+        Meta::Int(name, default, _, _) => (name, SettingValueEnum::Int(default)),
+        Meta::Float(name, default, change, _, _) => {
+            (name, SettingValueEnum::Float(default, change))
+        }
+        Meta::Vec3(name, default, change, _, _) => (name, SettingValueEnum::Vec3(default, change)),
+    }
+}
+
+// Fits a named resolution to the projection's aspect ratio, keeping the width, or the height for
+// the square fisheye. The width is the whole image's, side by side eyes get half of it each.
+pub fn projection_size(settings: &Settings, (width, height): (u32, u32)) -> (u32, u32) {
+    let stereo = settings.find("stereo").unwrap_u32() as u32;
+    let (width, height) = if stereo == STEREO_SIDE_BY_SIDE {
+        (width / 2, height / 2)
+    } else {
+        (width, height)
+    };
+    let (eye_width, eye_height) = match settings.find("projection").unwrap_u32() as u32 {
+        PROJECTION_EQUIRECTANGULAR => {
+            let angle = settings
+                .find("equirectangular_angle")
+                .unwrap_float()
+                .max(1e-3);
+            // rounded to an even height, truncating could turn 960 into 958 over a rounding error
+            let height = (width as f64 * std::f64::consts::PI / angle / 2.0).round() as u32 * 2;
+            (width, height)
+        }
+        PROJECTION_FISHEYE => (height, height),
+        PROJECTION_CUBEMAP => {
+            // square faces with an even size, for video encoders
+            let face = width / 6 * 2;
+            (face * 3, face * 2)
+        }
+        _ => (width, height),
+    };
+    match stereo {
+        STEREO_SIDE_BY_SIDE => (eye_width * 2, eye_height),
+        STEREO_OVER_UNDER => (eye_width, eye_height * 2),
+        _ => (eye_width, eye_height),
+    }
+}
+
 impl KernelUniforms {
     pub fn from_settings(settings: &Settings) -> Self {
         let mut result = KernelUniforms::default();
         for m in UNIFORM_METADATA {
-            match m {
-                Meta::Int(name, _, _, get_mut) => {
-                    *get_mut(&mut result) = settings.get(name).unwrap().unwrap_u32() as u32;
-                }
-                Meta::Float(name, _, _, _, get_mut) => {
-                    *get_mut(&mut result) = settings.get(name).unwrap().unwrap_float() as f32;
-                }
-                Meta::Vec3(name, _, _, _, get_mut) => {
-                    let v = settings.get(name).unwrap().unwrap_vec3();
-                    *get_mut(&mut result) = Vec4::new(v.x as f32, v.y as f32, v.z as f32, 0.0);
-                }
+            let (name, _) = default_setting(m);
+            read_meta(m, name, settings, &mut result);
+        }
+        for (index, light) in result.lights.iter_mut().enumerate() {
+            for m in LIGHT_METADATA {
+                let (name, _) = default_setting(m);
+                read_meta(m, &light_key(index, name), settings, light);
             }
         }
         result
@@ -317,26 +652,100 @@ impl KernelUniforms {
 
     pub fn fill_defaults(settings: &mut Settings) {
         for m in UNIFORM_METADATA {
-            match *m {
-                Meta::Int(name, default, _, _) => {
-                    let setting = SettingValueEnum::Int(default);
-                    settings
-                        .values
-                        .push(SettingValue::new(name.to_string(), setting));
-                }
-                Meta::Float(name, default, change, _, _) => {
-                    let setting = SettingValueEnum::Float(default, change);
-                    settings
-                        .values
-                        .push(SettingValue::new(name.to_string(), setting));
-                }
-                Meta::Vec3(name, default, change, _, _) => {
-                    let setting = SettingValueEnum::Vec3(default, change);
-                    settings
-                        .values
-                        .push(SettingValue::new(name.to_string(), setting));
+            let (name, setting) = default_setting(m);
+            settings
+                .values
+                .push(SettingValue::new(name.to_string(), setting));
+        }
+        for index in 0..MAX_LIGHTS {
+            for m in LIGHT_METADATA {
+                let (name, mut setting) = default_setting(m);
+                match (index, name, &setting) {
+                    (0, "type", _) => setting = SettingValueEnum::Int(LIGHT_CONSTANT as u64),
+                    (0, "intensity", &SettingValueEnum::Float(_, change)) => {
+                        setting = SettingValueEnum::Float(CONSTANT_LIGHT_INTENSITY, change)
+                    }
+                    _ => (),
                 }
+                settings
+                    .values
+                    .push(SettingValue::new(light_key(index, name), setting));
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(projection: u32, stereo: u32) -> Settings {
+        let mut settings = Settings::get_default();
+        settings
+            .find_mut("projection")
+            .set_value(SettingValueEnum::Int(projection as u64));
+        settings
+            .find_mut("stereo")
+            .set_value(SettingValueEnum::Int(stereo as u64));
+        settings
+    }
+
+    #[test]
+    fn projection_sizes() {
+        let size = (1920, 1080);
+        assert_eq!(projection_size(&settings(0, 0), size), (1920, 1080));
+        assert_eq!(
+            projection_size(&settings(PROJECTION_FISHEYE, 0), size),
+            (1080, 1080)
+        );
+        assert_eq!(
+            projection_size(&settings(PROJECTION_CUBEMAP, 0), size),
+            (1920, 1280)
+        );
+        // square faces with an even size
+        assert_eq!(
+            projection_size(&settings(PROJECTION_CUBEMAP, 0), (1000, 1000)),
+            (996, 664)
+        );
+    }
+
+    #[test]
+    fn stereo_sizes() {
+        let size = (1920, 1080);
+        assert_eq!(
+            projection_size(&settings(0, STEREO_SIDE_BY_SIDE), size),
+            (1920, 540)
+        );
+        assert_eq!(
+            projection_size(&settings(PROJECTION_FISHEYE, STEREO_SIDE_BY_SIDE), size),
+            (1080, 540)
+        );
+        assert_eq!(
+            projection_size(&settings(PROJECTION_CUBEMAP, STEREO_OVER_UNDER), size),
+            (1920, 2560)
+        );
+        // anaglyph keeps a single image
+        assert_eq!(projection_size(&settings(0, 3), size), (1920, 1080));
+    }
+
+    #[test]
+    fn equirectangular_sizes() {
+        let size = (1920, 1080);
+        assert_eq!(
+            projection_size(&settings(PROJECTION_EQUIRECTANGULAR, 0), size),
+            (1920, 960)
+        );
+        assert_eq!(
+            projection_size(
+                &settings(PROJECTION_EQUIRECTANGULAR, STEREO_OVER_UNDER),
+                size
+            ),
+            (1920, 1920)
+        );
+        // half a turn, as for VR180, is square
+        let mut half = settings(PROJECTION_EQUIRECTANGULAR, 0);
+        half.find_mut("equirectangular_angle")
+            .set_value(SettingValueEnum::Float(std::f64::consts::PI, 0.0));
+        assert_eq!(projection_size(&half, (1000, 500)), (1000, 1000));
+    }
+}
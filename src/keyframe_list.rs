@@ -1,14 +1,128 @@
 use crate::{setting_value::SettingValueEnum, settings::Settings, Error};
-use cgmath::Vector3;
+use cgmath::{InnerSpace, Vector3};
 use std::{
     fs::File,
     io::{BufRead, BufReader, BufWriter, Write},
 };
 
+#[derive(Clone, PartialEq)]
 pub struct KeyframeList {
     keyframes: Vec<Settings>,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PathSpeed {
+    // Each keyframe takes the same amount of time
+    Keyframe,
+    // The camera moves at a constant speed along the path
+    Constant,
+    // The camera moves at a constant speed relative to the local fractal scale (focal_distance)
+    Scaled,
+}
+
+impl PathSpeed {
+    pub fn next(self) -> Self {
+        match self {
+            PathSpeed::Keyframe => PathSpeed::Constant,
+            PathSpeed::Constant => PathSpeed::Scaled,
+            PathSpeed::Scaled => PathSpeed::Keyframe,
+        }
+    }
+}
+
+impl std::str::FromStr for PathSpeed {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("keyframe") {
+            Ok(PathSpeed::Keyframe)
+        } else if s.eq_ignore_ascii_case("constant") {
+            Ok(PathSpeed::Constant)
+        } else if s.eq_ignore_ascii_case("scaled") {
+            Ok(PathSpeed::Scaled)
+        } else {
+            Err("Invalid path speed".into())
+        }
+    }
+}
+
+// Maps evenly spaced times to evenly spaced distances along the camera path, by sampling the
+// cumulative arc length of the `pos` spline and inverting it.
+pub struct PathTiming {
+    distances: Vec<f64>,
+}
+
+const PATH_SAMPLES_PER_KEYFRAME: usize = 64;
+
+impl PathTiming {
+    pub fn identity() -> Self {
+        Self {
+            distances: Vec::new(),
+        }
+    }
+
+    pub fn new(speed: PathSpeed, segments: usize, interpolate: impl Fn(f64) -> Settings) -> Self {
+        if speed == PathSpeed::Keyframe || segments == 0 {
+            return Self::identity();
+        }
+        let count = segments * PATH_SAMPLES_PER_KEYFRAME;
+        let mut distances = Vec::with_capacity(count + 1);
+        let mut total = 0.0;
+        let mut previous = interpolate(0.0);
+        distances.push(total);
+        for i in 1..=count {
+            let current = interpolate(i as f64 / count as f64);
+            let delta = current.find("pos").unwrap_vec3() - previous.find("pos").unwrap_vec3();
+            let mut length = delta.magnitude();
+            if speed == PathSpeed::Scaled {
+                let scale = (current.find("focal_distance").unwrap_float()
+                    + previous.find("focal_distance").unwrap_float())
+                    / 2.0;
+                if scale > 0.0 {
+                    length /= scale;
+                }
+            }
+            total += length;
+            distances.push(total);
+            previous = current;
+        }
+        Self { distances }
+    }
+
+    pub fn map(&self, time: f64) -> f64 {
+        let total = match self.distances.last() {
+            Some(&total) if total > 0.0 => total,
+            _ => return time,
+        };
+        let target = time.clamp(0.0, 1.0) * total;
+        let index = self
+            .distances
+            .partition_point(|&distance| distance <= target)
+            .clamp(1, self.distances.len() - 1);
+        let before = self.distances[index - 1];
+        let after = self.distances[index];
+        let frac = if after > before {
+            (target - before) / (after - before)
+        } else {
+            0.0
+        };
+        (index as f64 - 1.0 + frac) / (self.distances.len() - 1) as f64
+    }
+
+    pub fn unmap(&self, time: f64) -> f64 {
+        let total = match self.distances.last() {
+            Some(&total) if total > 0.0 => total,
+            _ => return time,
+        };
+        let position = time.clamp(0.0, 1.0) * (self.distances.len() - 1) as f64;
+        let index = (position as usize).min(self.distances.len() - 2);
+        let frac = position - index as f64;
+        let distance =
+            self.distances[index] + (self.distances[index + 1] - self.distances[index]) * frac;
+        distance / total
+    }
+}
+
 fn interpolate_float(p0: f64, p1: f64, p2: f64, p3: f64, t: f64, linear: bool) -> f64 {
     if linear {
         p1 + (p2 - p1) * t
@@ -24,6 +138,32 @@ fn interpolate_float(p0: f64, p1: f64, p2: f64, p3: f64, t: f64, linear: bool) -
     }
 }
 
+// Settings with a negative change rate are edited multiplicatively, so interpolate them in log
+// space. Falls back to linear space if any value can't be logged.
+fn interpolate_float_exp(p0: f64, p1: f64, p2: f64, p3: f64, t: f64, linear: bool) -> f64 {
+    if p0 > 0.0 && p1 > 0.0 && p2 > 0.0 && p3 > 0.0 {
+        interpolate_float(p0.ln(), p1.ln(), p2.ln(), p3.ln(), t, linear).exp()
+    } else {
+        interpolate_float(p0, p1, p2, p3, t, linear)
+    }
+}
+
+fn interpolate_float_change(
+    p0: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    t: f64,
+    linear: bool,
+    change: f64,
+) -> f64 {
+    if change < 0.0 {
+        interpolate_float_exp(p0, p1, p2, p3, t, linear)
+    } else {
+        interpolate_float(p0, p1, p2, p3, t, linear)
+    }
+}
+
 fn interpolate_vec3(
     p0: Vector3<f64>,
     p1: Vector3<f64>,
@@ -31,14 +171,30 @@ fn interpolate_vec3(
     p3: Vector3<f64>,
     t: f64,
     linear: bool,
+    change: f64,
 ) -> Vector3<f64> {
     Vector3::new(
-        interpolate_float(p0.x, p1.x, p2.x, p3.x, t, linear),
-        interpolate_float(p0.y, p1.y, p2.y, p3.y, t, linear),
-        interpolate_float(p0.z, p1.z, p2.z, p3.z, t, linear),
+        interpolate_float_change(p0.x, p1.x, p2.x, p3.x, t, linear, change),
+        interpolate_float_change(p0.y, p1.y, p2.y, p3.y, t, linear, change),
+        interpolate_float_change(p0.z, p1.z, p2.z, p3.z, t, linear, change),
     )
 }
 
+// When zooming exponentially, the camera should cover distance proportionally to the current
+// scale, otherwise it rushes through the zoomed-in part of the path. With the scale interpolated
+// as `cur * ratio^t`, the fraction of the path covered at time `t` is `(ratio^t - 1) / (ratio - 1)`.
+pub fn zoom_time(scale_cur: f64, scale_next: f64, t: f64) -> f64 {
+    if scale_cur <= 0.0 || scale_next <= 0.0 {
+        return t;
+    }
+    let ratio = scale_next / scale_cur;
+    if (ratio - 1.0).abs() < 1e-6 {
+        t
+    } else {
+        (ratio.powf(t) - 1.0) / (ratio - 1.0)
+    }
+}
+
 fn interpolate_int(prev: u64, cur: u64, next: u64, next2: u64, time: f64, linear: bool) -> u64 {
     interpolate_float(
         prev as f64,
@@ -51,7 +207,7 @@ fn interpolate_int(prev: u64, cur: u64, next: u64, next2: u64, time: f64, linear
     .round() as u64
 }
 
-fn interpolate(
+pub fn interpolate_value(
     prev: &SettingValueEnum,
     cur: &SettingValueEnum,
     next: &SettingValueEnum,
@@ -72,7 +228,7 @@ fn interpolate(
             &SettingValueEnum::Float(next, _),
             &SettingValueEnum::Float(next2, _),
         ) => SettingValueEnum::Float(
-            interpolate_float(prev, cur, next, next2, time, linear),
+            interpolate_float_change(prev, cur, next, next2, time, linear, delta),
             delta,
         ),
         (
@@ -81,9 +237,10 @@ fn interpolate(
             &SettingValueEnum::Vec3(next, _),
             &SettingValueEnum::Vec3(next2, _),
         ) => SettingValueEnum::Vec3(
-            interpolate_vec3(prev, cur, next, next2, time, linear),
+            interpolate_vec3(prev, cur, next, next2, time, linear, delta),
             delta,
         ),
+        (_, SettingValueEnum::Text(cur), _, _) => SettingValueEnum::Text(cur.clone()),
         _ => panic!("Inconsistent keyframe types"),
     }
 }
@@ -132,6 +289,40 @@ impl KeyframeList {
         self.keyframes.push(keyframe);
     }
 
+    pub fn get(&self, index: usize) -> Option<&Settings> {
+        self.keyframes.get(index)
+    }
+
+    pub fn insert(&mut self, index: usize, keyframe: Settings) {
+        self.keyframes.insert(index, keyframe);
+    }
+
+    pub fn replace(&mut self, index: usize, keyframe: Settings) {
+        self.keyframes[index] = keyframe;
+    }
+
+    pub fn remove(&mut self, index: usize) -> Settings {
+        self.keyframes.remove(index)
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.keyframes.swap(a, b);
+    }
+
+    // The time (as passed to `interpolate`) at which the given keyframe is hit exactly
+    pub fn keyframe_time(&self, index: usize, wrap: bool) -> f64 {
+        let timelen = if wrap {
+            self.keyframes.len()
+        } else {
+            self.keyframes.len() - 1
+        };
+        if timelen == 0 {
+            0.0
+        } else {
+            index as f64 / timelen as f64
+        }
+    }
+
     fn clamp(&self, index: isize, wrap: bool) -> usize {
         let len = self.keyframes.len();
         if wrap {
@@ -148,18 +339,29 @@ impl KeyframeList {
             self.keyframes.len() - 1
         };
         let time = time * timelen as f64;
-        let index_cur = time as usize;
-        let time = time - index_cur as f64;
+        let index_floor = time.floor();
+        let time = time - index_floor;
+        let index_cur = self.clamp(index_floor as isize, wrap);
         let index_prev = self.clamp(index_cur as isize - 1, wrap);
         let index_next = self.clamp(index_cur as isize + 1, wrap);
         let index_next2 = self.clamp(index_cur as isize + 2, wrap);
         let mut base = self.keyframes[index_cur].clone();
+        let pos_time = zoom_time(
+            self.keyframes[index_cur]
+                .find("focal_distance")
+                .unwrap_float(),
+            self.keyframes[index_next]
+                .find("focal_distance")
+                .unwrap_float(),
+            time,
+        );
         for value in &mut base.values {
+            let time = if value.key() == "pos" { pos_time } else { time };
             let prev = self.keyframes[index_prev].find(value.key()).value();
             let cur = self.keyframes[index_cur].find(value.key()).value();
             let next = self.keyframes[index_next].find(value.key()).value();
             let next2 = self.keyframes[index_next2].find(value.key()).value();
-            let result = interpolate(
+            let result = interpolate_value(
                 prev,
                 cur,
                 next,
@@ -172,4 +374,124 @@ impl KeyframeList {
         base.normalize();
         base
     }
+
+    pub fn timing(&self, wrap: bool, speed: PathSpeed) -> PathTiming {
+        if self.keyframes.len() < 2 {
+            return PathTiming::identity();
+        }
+        let segments = if wrap {
+            self.keyframes.len()
+        } else {
+            self.keyframes.len() - 1
+        };
+        PathTiming::new(speed, segments, |time| self.interpolate(time, wrap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoom_time_ends() {
+        for (cur, next) in [(1.0, 4.0), (4.0, 1.0), (1.0, 1e-3)] {
+            assert!(zoom_time(cur, next, 0.0).abs() < 1e-12);
+            assert!((zoom_time(cur, next, 1.0) - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn zoom_time_follows_scale() {
+        // the scale doubles halfway, a third of the way from 1 to 4
+        assert!((zoom_time(1.0, 4.0, 0.5) - 1.0 / 3.0).abs() < 1e-12);
+        // zooming in covers the early, large scale part faster
+        assert!((zoom_time(4.0, 1.0, 0.5) - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(zoom_time(2.0, 2.0, 0.3), 0.3);
+        assert_eq!(zoom_time(0.0, 2.0, 0.3), 0.3);
+        assert_eq!(zoom_time(2.0, -1.0, 0.3), 0.3);
+    }
+
+    // Two keyframes one unit apart along x that zoom in by 4, so `pos` moves unevenly in time
+    fn zooming_keyframes() -> KeyframeList {
+        let mut keyframes = KeyframeList::new();
+        for (x, focal_distance) in [(0.0, 4.0), (1.0, 1.0)] {
+            let mut settings = Settings::get_default();
+            *settings.find_mut("pos").unwrap_vec3_mut() = Vector3::new(x, 0.0, 0.0);
+            let change = match settings.find("focal_distance").value() {
+                SettingValueEnum::Float(_, change) => *change,
+                _ => unreachable!(),
+            };
+            settings
+                .find_mut("focal_distance")
+                .set_value(SettingValueEnum::Float(focal_distance, change));
+            keyframes.push(settings);
+        }
+        keyframes
+    }
+
+    fn x_at(keyframes: &KeyframeList, time: f64) -> f64 {
+        keyframes
+            .interpolate(time, false)
+            .find("pos")
+            .unwrap_vec3()
+            .x
+    }
+
+    #[test]
+    fn path_timing_round_trip() {
+        let keyframes = zooming_keyframes();
+        assert!((x_at(&keyframes, 0.5) - 0.5).abs() > 0.1);
+        // the share of the path covered at a mapped time is the time itself
+        let timing = keyframes.timing(false, PathSpeed::Constant);
+        for i in 0..=20 {
+            let t = i as f64 / 20.0;
+            let x = x_at(&keyframes, timing.map(t));
+            assert!((x - t).abs() < 1e-3, "{} at {}", x, t);
+        }
+    }
+
+    #[test]
+    fn path_timing_monotonic() {
+        let timing = zooming_keyframes().timing(false, PathSpeed::Constant);
+        // stretches without movement are skipped, never walked backwards
+        let still = PathTiming {
+            distances: vec![0.0, 1.0, 1.0, 1.0, 3.0],
+        };
+        for timing in [timing, still] {
+            let mut previous = timing.map(0.0);
+            assert_eq!(previous, 0.0);
+            for i in 1..=1000 {
+                let time = timing.map(i as f64 / 1000.0);
+                assert!(time >= previous, "{} after {}", time, previous);
+                previous = time;
+            }
+            assert!((previous - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn path_timing_identity() {
+        let keyframes = zooming_keyframes();
+        let mut still = KeyframeList::new();
+        still.push(Settings::get_default());
+        still.push(Settings::get_default());
+        for timing in [
+            PathTiming::identity(),
+            keyframes.timing(false, PathSpeed::Keyframe),
+            still.timing(false, PathSpeed::Constant),
+        ] {
+            assert_eq!(timing.map(0.25), 0.25);
+        }
+    }
+
+    #[test]
+    fn path_timing_unmap_inverts_map() {
+        let timing = zooming_keyframes().timing(false, PathSpeed::Constant);
+        for i in 0..=20 {
+            let t = i as f64 / 20.0;
+            assert!((timing.unmap(timing.map(t)) - t).abs() < 1e-9);
+            assert!((timing.map(timing.unmap(t)) - t).abs() < 1e-9);
+        }
+        assert_eq!(PathTiming::identity().unmap(0.75), 0.75);
+    }
 }
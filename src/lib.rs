@@ -1,33 +1,48 @@
+mod audio;
 mod buffer_blit;
+mod denoise;
 mod fps_counter;
+mod frame_encoder;
 mod input;
 mod interactive;
 mod kernel;
 mod kernel_uniforms;
 mod keyframe_list;
+mod palette;
+mod passes;
+mod path_overlay;
 mod progress;
 mod render_window;
 mod setting_value;
 mod settings;
 mod settings_input;
+mod sky;
+mod track_list;
+mod video_profile;
 
+use audio::{AudioFeatures, AudioMapping};
 use cgmath::Vector3;
 use chrono::prelude::*;
-use kernel::Kernel;
-use keyframe_list::KeyframeList;
+use frame_encoder::AnimationWriter;
+use kernel::{Kernel, PendingDownload, PixelStats, SampleBatching};
+use keyframe_list::{KeyframeList, PathSpeed};
 use log::info;
-use png::{BitDepth, ColorType, Encoder};
+use png::{BitDepth, ColorType, Decoder, Encoder};
 use progress::Progress;
 use settings::Settings;
 use std::{
+    collections::BTreeMap,
     env::args,
     fs::File,
-    io::{BufWriter, Write},
+    io::{BufReader, BufWriter, Write},
     mem::drop,
+    path::Path,
     process::{Command, Stdio},
     str,
-    sync::mpsc,
+    sync::{mpsc, Arc, Mutex},
 };
+use track_list::TrackList;
+use video_profile::{OutputKind, VideoProfile};
 
 use winit::keyboard::KeyCode as Key;
 
@@ -36,20 +51,17 @@ pub type Error = Box<dyn std::error::Error>;
 pub struct CpuTexture {
     data: Vec<u8>,
     size: (u32, u32),
+    // RGBA with straight alpha instead of RGB
+    alpha: bool,
 }
 
 impl CpuTexture {
-    fn rgba_to_rgb(&mut self) {
-        let mut index = 0;
-        self.data.retain(|_| {
-            index += 1;
-            if index == 4 {
-                index = 0;
-                false
-            } else {
-                true
-            }
-        })
+    fn color_type(&self) -> ColorType {
+        if self.alpha {
+            ColorType::Rgba
+        } else {
+            ColorType::Rgb
+        }
     }
 }
 
@@ -78,83 +90,226 @@ fn save_image(image: &CpuTexture, path: &str) -> Result<(), Error> {
 
 fn write_image(image: &CpuTexture, w: impl Write) -> Result<(), Error> {
     let mut encoder = Encoder::new(w, image.size.0, image.size.1);
-    encoder.set_color(ColorType::RGB);
+    encoder.set_color(image.color_type());
     encoder.set_depth(BitDepth::Eight);
     let mut writer = encoder.write_header()?;
     writer.write_image_data(&image.data)?;
     Ok(())
 }
 
-#[cfg(not(windows))]
-fn progress_count(rpp: usize) -> usize {
-    (rpp / 20).min(4).max(16)
+fn load_image(path: &str) -> Result<CpuTexture, Error> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?));
+    let mut reader = decoder.read_info()?;
+    let alpha = match reader.output_color_type() {
+        (ColorType::Rgb, BitDepth::Eight) => false,
+        (ColorType::Rgba, BitDepth::Eight) => true,
+        _ => return Err(format!("Expected an 8 bit RGB or RGBA image: {}", path).into()),
+    };
+    let mut data = vec![0; reader.output_buffer_size().ok_or("Image too large")?];
+    let info = reader.next_frame(&mut data)?;
+    data.truncate(info.buffer_size());
+    Ok(CpuTexture {
+        data,
+        size: (info.width, info.height),
+        alpha,
+    })
+}
+
+// Options for a single --render image
+struct RenderOptions {
+    batching: SampleBatching,
+    // stop once every pixel's estimated noise is below this, enables adaptive sampling
+    target_noise: Option<f32>,
+    min_samples: u32,
+    // stop after this many seconds
+    max_time: Option<f64>,
+    heatmap: bool,
+    denoise: bool,
+    passes: bool,
+    transparent: bool,
 }
 
-// Special windows handling for TDR
-#[cfg(windows)]
-fn progress_count(_: usize) -> usize {
-    1
+impl RenderOptions {
+    fn parse(args: &[String]) -> Result<Self, Error> {
+        let mut result = Self {
+            batching: SampleBatching::default(),
+            target_noise: None,
+            min_samples: 16,
+            max_time: None,
+            heatmap: false,
+            denoise: false,
+            passes: false,
+            transparent: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--target-noise" => result.target_noise = Some(value()?.parse()?),
+                "--min-samples" => result.min_samples = value()?.parse::<u32>()?.max(2),
+                "--max-time" => result.max_time = Some(value()?.parse()?),
+                "--heatmap" => result.heatmap = true,
+                "--denoise" => result.denoise = true,
+                "--passes" => result.passes = true,
+                "--transparent" => result.transparent = true,
+                arg => {
+                    if !parse_batching_option(&mut result.batching, arg, &mut value)? {
+                        return Err(format!("Unknown render option: {}", arg).into());
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Returns false if `arg` isn't a batching option
+fn parse_batching_option<'a>(
+    batching: &mut SampleBatching,
+    arg: &str,
+    value: &mut impl FnMut() -> Result<&'a String, String>,
+) -> Result<bool, Error> {
+    match arg {
+        "--samples-per-dispatch" => batching.samples_per_dispatch = value()?.parse::<u32>()?.max(1),
+        "--time-budget" => {
+            let budget: f64 = value()?.parse()?;
+            batching.time_budget = if budget > 0.0 { Some(budget) } else { None };
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
 }
 
 fn image(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    width: u32,
-    height: u32,
+    resolution: Resolution,
     rpp: usize,
+    options: RenderOptions,
 ) -> Result<(), Error> {
+    // with a noise or time target, rpp is the maximum and 0 means no maximum
+    let has_target = options.target_noise.is_some() || options.max_time.is_some();
+    if rpp == 0 && !has_target {
+        return Err("rpp 0 needs --target-noise or --max-time".into());
+    }
+    let max_samples = if rpp == 0 { usize::MAX } else { rpp };
     let loaded_settings = Settings::load("settings.clam5", &Settings::get_default())?;
+    let (width, height) = resolution.size(&loaded_settings);
     let mut kernel = Kernel::create(device, queue, width, height);
+    if let Some(target_noise) = options.target_noise {
+        kernel.set_adaptive(target_noise, options.min_samples);
+    }
+    kernel.set_transparent(options.transparent);
     let progress = Progress::new();
-    let progress_count = progress_count(rpp);
-    let mut encoder =
-        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-    for ray in 0..rpp {
-        if ray > 0 && ray % progress_count == 0 {
-            queue.submit(std::iter::once(encoder.finish()));
-            encoder =
-                device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-            device.poll(wgpu::Maintain::Wait);
-            let value = ray as f64 / rpp as f64;
-            info!("{}", progress.time_str(value));
+    // report progress about every 5%
+    let per_dispatch = options.batching.samples_per_dispatch as usize;
+    let chunk = if rpp == 0 {
+        per_dispatch * 4
+    } else {
+        (rpp / 20).max(per_dispatch)
+    };
+    let mut done = 0;
+    while done < max_samples {
+        let samples = chunk.min(max_samples - done);
+        kernel.render(device, queue, &loaded_settings, samples, options.batching);
+        device.poll(wgpu::Maintain::Wait);
+        done += samples;
+        if let Some(target_noise) = options.target_noise {
+            let stats = kernel.download_stats(device, queue);
+            let converged = stats
+                .iter()
+                .filter(|pixel| pixel.count >= options.min_samples && pixel.noise() < target_noise)
+                .count();
+            info!(
+                "{} samples, {:.2}% of pixels converged, {:.2}s elapsed",
+                done,
+                100.0 * converged as f64 / stats.len() as f64,
+                progress.elapsed()
+            );
+            if converged == stats.len() {
+                info!("target noise reached");
+                break;
+            }
+        } else if rpp == 0 {
+            info!("{} samples, {:.2}s elapsed", done, progress.elapsed());
+        } else {
+            info!("{}", progress.time_str(done as f64 / rpp as f64));
+        }
+        if options
+            .max_time
+            .is_some_and(|max_time| progress.elapsed() >= max_time)
+        {
+            info!("time limit reached");
+            break;
         }
-        kernel.run(device, &mut encoder, &loaded_settings);
     }
-    queue.submit(std::iter::once(encoder.finish()));
-    device.poll(wgpu::Maintain::Wait);
+    if options.denoise {
+        info!("denoising");
+        kernel.set_denoise(true);
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        kernel.denoise(device, &mut encoder);
+        queue.submit(std::iter::once(encoder.finish()));
+    }
     info!("render done, downloading");
     let image = kernel.download(device, queue);
-    info!("saving, final time: {}", progress.time_str(1.0));
+    info!("saving, final time: {:.2}s", progress.elapsed());
     let local: DateTime<Local> = Local::now();
-    let filename = local.format("%Y-%m-%d_%H-%M-%S.png").to_string();
-    save_image(&image, &filename)?;
+    let filename = local.format("%Y-%m-%d_%H-%M-%S").to_string();
+    save_image(&image, &format!("{}.png", filename))?;
+    if options.heatmap {
+        let stats = kernel.download_stats(device, queue);
+        let heatmap = noise_heatmap(&stats, kernel.texture_size(), options.target_noise);
+        save_image(&heatmap, &format!("{}_noise.png", filename))?;
+    }
+    if options.passes {
+        let aovs = kernel.download_aovs(device, queue);
+        passes::save_passes(&aovs, kernel.texture_size(), &filename)?;
+    }
     info!("done");
     Ok(())
 }
 
-enum VideoFormat {
-    MP4,
-    Twitter,
-    PngSeq,
-    Gif,
-}
-
-impl std::str::FromStr for VideoFormat {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.eq_ignore_ascii_case("mp4") {
-            Ok(VideoFormat::MP4)
-        } else if s.eq_ignore_ascii_case("twitter") {
-            Ok(VideoFormat::Twitter)
-        } else if s.eq_ignore_ascii_case("pngseq") {
-            Ok(VideoFormat::PngSeq)
-        } else if s.eq_ignore_ascii_case("gif") {
-            Ok(VideoFormat::Gif)
-        } else {
-            Err("Invalid video format".into())
+// Debug image of the estimated noise per pixel, black is noise free, white is at or above the
+// target noise (or the highest noise in the image without a target)
+fn noise_heatmap(stats: &[PixelStats], size: (u32, u32), target: Option<f32>) -> CpuTexture {
+    let max = target.unwrap_or_else(|| {
+        stats
+            .iter()
+            .map(PixelStats::noise)
+            .filter(|noise| noise.is_finite())
+            .fold(0.0, f32::max)
+    });
+    const RAMP: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 1.0],
+    ];
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let mut data = Vec::with_capacity(width * height * 3);
+    // the kernel image is stored bottom row first
+    for y in (0..height).rev() {
+        for pixel in &stats[y * width..(y + 1) * width] {
+            let value = if max > 0.0 {
+                (pixel.noise() / max).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let position = value * (RAMP.len() - 1) as f32;
+            let index = (position as usize).min(RAMP.len() - 2);
+            let t = position - index as f32;
+            for (low, high) in RAMP[index].iter().zip(&RAMP[index + 1]) {
+                let color = low * (1.0 - t) + high * t;
+                data.push((color * 255.0).round() as u8);
+            }
         }
     }
+    CpuTexture {
+        data,
+        size,
+        alpha: false,
+    }
 }
 
 fn video_one(
@@ -163,23 +318,10 @@ fn video_one(
     rpp: usize,
     kernel: &mut Kernel,
     settings: &Settings,
-    stream: &mpsc::SyncSender<CpuTexture>,
-) -> Result<(), Error> {
-    let mut encoder =
-        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-    for i in 0..rpp {
-        if cfg!(windows) && i % 64 == 0 {
-            queue.submit(std::iter::once(encoder.finish()));
-            encoder =
-                device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-            device.poll(wgpu::Maintain::Wait);
-        }
-        kernel.run(device, &mut encoder, settings);
-    }
-    queue.submit(std::iter::once(encoder.finish()));
-    let image = kernel.download(device, queue);
-    stream.send(image)?;
-    Ok(())
+    batching: SampleBatching,
+) -> PendingDownload {
+    kernel.render(device, queue, settings, rpp, batching);
+    kernel.start_download(device, queue)
 }
 
 fn ffmpeg(args: &[&str]) -> Result<(), Error> {
@@ -195,60 +337,129 @@ fn ffmpeg(args: &[&str]) -> Result<(), Error> {
     }
 }
 
-fn do_gifize() -> Result<(), Error> {
-    ffmpeg(&["-i", "%04d.png", "-vf", "palettegen", "-y", "palette.png"])?;
-    ffmpeg(&[
-        "-framerate",
-        "50",
-        "-i",
-        "%04d.png",
-        "-i",
-        "palette.png",
-        "-lavfi",
-        "paletteuse",
-        "-y",
-        "output.gif",
-    ])?;
-    Ok(())
+fn frame_stem(dir: &str, frame: usize) -> String {
+    format!("{}/{:04}", dir, frame)
 }
 
-fn pngseq_write(stream: &mpsc::Receiver<CpuTexture>, gifize: bool) -> Result<(), Error> {
-    let mut i = 0;
-    if gifize {
-        for item in std::fs::read_dir(".")? {
-            let item = item?;
-            let is_num = item.path().file_stem().map_or(false, |x| {
-                x.to_str().map_or(false, |x| x.parse::<u64>().is_ok())
-            });
-            if is_num {
-                std::fs::remove_file(item.path())?;
-            }
+fn frame_path(dir: &str, frame: usize) -> String {
+    format!("{}.png", frame_stem(dir, frame))
+}
+
+// Removes previously rendered frames, so they don't end up in an assembled gif/video
+fn clear_frames(dir: &str) -> Result<(), Error> {
+    for item in std::fs::read_dir(dir)? {
+        let path = item?.path();
+        let is_frame = path.extension().is_some_and(|x| x == "png")
+            && path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| x.parse::<u64>().is_ok());
+        if is_frame {
+            std::fs::remove_file(path)?;
         }
     }
-    while let Ok(img) = stream.recv() {
-        save_image(&img, &format!("{:04}.png", i))?;
-        i += 1;
+    Ok(())
+}
+
+// Encodes frames to png on `threads` threads, the results arrive in completion order
+fn png_encode_pool(
+    stream: mpsc::Receiver<(usize, CpuTexture)>,
+    threads: usize,
+) -> mpsc::Receiver<(usize, Vec<u8>)> {
+    let stream = Arc::new(Mutex::new(stream));
+    let (send, recv) = mpsc::sync_channel(threads * 2);
+    for _ in 0..threads {
+        let stream = stream.clone();
+        let send = send.clone();
+        std::thread::spawn(move || loop {
+            let next = stream.lock().unwrap().recv();
+            let (frame, img) = match next {
+                Ok(next) => next,
+                Err(_) => break,
+            };
+            let mut png = Vec::new();
+            write_image(&img, &mut png).expect("Couldn't encode frame");
+            if send.send((frame, png)).is_err() {
+                break;
+            }
+        });
     }
-    if gifize {
-        do_gifize()?;
+    recv
+}
+
+fn pngseq_write(stream: &mpsc::Receiver<(usize, Vec<u8>)>, dir: &str) -> Result<(), Error> {
+    while let Ok((frame, png)) = stream.recv() {
+        // written under another name and renamed when complete, so a crash can't leave a
+        // truncated frame that --resume would skip
+        let path = frame_path(dir, frame);
+        let partial = format!("{}.tmp", path);
+        std::fs::write(&partial, png)?;
+        std::fs::rename(partial, path)?;
     }
     Ok(())
 }
 
-fn video_write_from_pngseq(twitter: bool) -> Result<(), Error> {
-    let mut args = Vec::new();
-    args.extend_from_slice(&["-framerate", "60", "-i", "%04d.png"]);
-    if twitter {
-        args.extend_from_slice(&["-c:v", "libx264", "-pix_fmt", "yuv420p", "-b:v", "2048K"]);
-    } else {
-        // video is corrupted otherwise for some reason
-        args.extend_from_slice(&["-pix_fmt", "yuv420p"]);
+fn animation_write(
+    stream: &mpsc::Receiver<(usize, CpuTexture)>,
+    mut writer: AnimationWriter,
+) -> Result<(), Error> {
+    while let Ok((_, img)) = stream.recv() {
+        writer.write_frame(&img)?;
+    }
+    writer.finish()
+}
+
+// Sorted paths of the numbered frames in `dir`
+fn list_frames(dir: &str) -> Result<Vec<String>, Error> {
+    let mut frames = Vec::new();
+    for item in std::fs::read_dir(dir)? {
+        let path = item?.path();
+        let number = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<u64>().ok());
+        if let (Some(number), true) = (number, path.extension().is_some_and(|x| x == "png")) {
+            frames.push((number, path.to_string_lossy().into_owned()));
+        }
     }
-    args.extend_from_slice(&["video.mp4", "-y"]);
+    frames.sort();
+    Ok(frames.into_iter().map(|(_, path)| path).collect())
+}
+
+fn animation_write_from_pngseq(dir: &str, profile: &VideoProfile) -> Result<(), Error> {
+    let frames = list_frames(dir)?;
+    let first = load_image(frames.first().ok_or("No frames to assemble")?)?;
+    let output = format!("{}/{}.{}", dir, profile.output_name, profile.extension());
+    let mut writer = AnimationWriter::new(profile, &output, first.size, frames.len(), first.alpha)?;
+    writer.write_frame(&first)?;
+    for frame in &frames[1..] {
+        let image = load_image(frame)?;
+        if image.size != first.size {
+            return Err(format!("Frame size mismatch: {}", frame).into());
+        }
+        writer.write_frame(&image)?;
+    }
+    writer.finish()
+}
+
+fn video_write_from_pngseq(dir: &str, profile: &VideoProfile) -> Result<(), Error> {
+    let frames = format!("{}/%04d.png", dir);
+    let output = format!("{}/{}.{}", dir, profile.output_name, profile.extension());
+    let fps = profile.fps_arg();
+    let encode_args = profile.encode_args(true);
+    let mut args = vec!["-framerate", &fps, "-i", &frames];
+    args.extend(encode_args.iter().map(String::as_str));
+    args.extend_from_slice(&[&output, "-y"]);
     ffmpeg(&args)
 }
 
-fn video_write(stream: &mpsc::Receiver<CpuTexture>, twitter: bool) -> Result<(), Error> {
+fn video_write(
+    stream: &mpsc::Receiver<(usize, Vec<u8>)>,
+    first_frame: usize,
+    output: &str,
+    profile: &VideoProfile,
+    audio: Option<String>,
+) -> Result<(), Error> {
     let exe = if cfg!(windows) {
         "ffmpeg.exe"
     } else {
@@ -256,18 +467,33 @@ fn video_write(stream: &mpsc::Receiver<CpuTexture>, twitter: bool) -> Result<(),
     };
     let mut ffmpeg = Command::new(exe);
     ffmpeg.stdin(Stdio::piped());
-    ffmpeg.args(["-f", "image2pipe", "-framerate", "60", "-i", "-"]);
-    if twitter {
-        ffmpeg.args(["-c:v", "libx264", "-pix_fmt", "yuv420p", "-b:v", "2048K"]);
+    ffmpeg.args([
+        "-f",
+        "image2pipe",
+        "-framerate",
+        &profile.fps_arg(),
+        "-i",
+        "-",
+    ]);
+    if let Some(audio) = &audio {
+        ffmpeg.args(["-i", audio, "-c:a", "aac", "-shortest"]);
     }
-    ffmpeg.args(["video.mp4", "-y"]);
+    ffmpeg.args(profile.encode_args(false));
+    ffmpeg.args([output, "-y"]);
     let mut ffmpeg = ffmpeg.spawn()?;
-    while let Ok(img) = stream.recv() {
+    // frames come out of the encoder pool out of order
+    let mut encoded = BTreeMap::new();
+    let mut next_frame = first_frame;
+    while let Ok((frame, png)) = stream.recv() {
+        encoded.insert(frame, png);
         let ffmpeg_stdin = ffmpeg
             .stdin
             .as_mut()
             .expect("ffmpeg process failed to redirect stdin");
-        write_image(&img, ffmpeg_stdin)?;
+        while let Some(png) = encoded.remove(&next_frame) {
+            ffmpeg_stdin.write_all(&png)?;
+            next_frame += 1;
+        }
     }
     // make sure to drop stdin to close process before waiting
     ffmpeg.stdin = None;
@@ -279,110 +505,345 @@ fn video_write(stream: &mpsc::Receiver<CpuTexture>, twitter: bool) -> Result<(),
     }
 }
 
+struct VideoOptions {
+    speed: PathSpeed,
+    audio: Option<String>,
+    audio_map: String,
+    mux_audio: bool,
+    start: usize,
+    end: Option<usize>,
+    resume: bool,
+    output_dir: String,
+    pipeline: bool,
+    encode_threads: usize,
+    denoise: bool,
+    passes: bool,
+    batching: SampleBatching,
+    profile_overrides: Vec<(String, String)>,
+}
+
+impl VideoOptions {
+    fn parse(args: &[String]) -> Result<Self, Error> {
+        let mut result = Self {
+            speed: PathSpeed::Keyframe,
+            audio: None,
+            audio_map: "audio.clam5".to_string(),
+            mux_audio: false,
+            start: 0,
+            end: None,
+            resume: false,
+            output_dir: "video".to_string(),
+            pipeline: true,
+            encode_threads: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            denoise: false,
+            passes: false,
+            batching: SampleBatching::default(),
+            profile_overrides: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--speed" => result.speed = value()?.parse()?,
+                "--audio" => result.audio = Some(value()?.clone()),
+                "--audio-map" => result.audio_map = value()?.clone(),
+                "--mux-audio" => result.mux_audio = true,
+                "--start" => result.start = value()?.parse()?,
+                "--end" => result.end = Some(value()?.parse()?),
+                "--resume" => result.resume = true,
+                "--out" => result.output_dir = value()?.clone(),
+                "--no-pipeline" => result.pipeline = false,
+                "--encode-threads" => result.encode_threads = value()?.parse::<usize>()?.max(1),
+                "--denoise" => result.denoise = true,
+                "--passes" => result.passes = true,
+                "--transparent" => result
+                    .profile_overrides
+                    .push(("alpha".to_string(), "true".to_string())),
+                "--fps" | "--codec" | "--crf" | "--bitrate" | "--pixel-format" | "--container"
+                | "--output" | "--dither" | "--ffmpeg-args" => {
+                    let key = match arg.as_str() {
+                        "--pixel-format" => "pixel_format",
+                        "--ffmpeg-args" => "args",
+                        arg => &arg[2..],
+                    };
+                    result
+                        .profile_overrides
+                        .push((key.to_string(), value()?.clone()));
+                }
+                arg => {
+                    if !parse_batching_option(&mut result.batching, arg, &mut value)? {
+                        return Err(format!("Unknown video option: {}", arg).into());
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn video(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    width: u32,
-    height: u32,
+    resolution: Resolution,
     rpp: usize,
     frames: usize,
     wrap: bool,
-    format: VideoFormat,
+    mut profile: VideoProfile,
+    options: VideoOptions,
 ) -> Result<(), Error> {
-    let keyframes = KeyframeList::load("keyframes.clam5", Settings::get_default())?;
+    for (key, value) in &options.profile_overrides {
+        profile.set(key, value)?;
+    }
+    profile.check_alpha()?;
+    let start = options.start;
+    let end = options.end.unwrap_or(frames).min(frames);
+    if start >= end {
+        return Err(format!("Empty frame range: {}..{}", start, end).into());
+    }
+    let whole = start == 0 && end == frames;
+    let is_pngseq = profile.kind == OutputKind::PngSeq;
+    if options.resume && !is_pngseq {
+        return Err("--resume is only supported for the pngseq format".into());
+    }
+    let dir = options.output_dir.clone();
+    std::fs::create_dir_all(&dir)?;
+    if is_pngseq && whole && !options.resume {
+        clear_frames(&dir)?;
+    }
+
+    // tracks.clam5 takes priority, otherwise the keyframes are converted to tracks
+    let file = if Path::new("tracks.clam5").exists() {
+        "tracks.clam5"
+    } else {
+        "keyframes.clam5"
+    };
+    let keyframes = TrackList::load(file, Settings::get_default(), wrap)?;
+    let timing = keyframes.timing(wrap, options.speed);
+    let (width, height) = resolution.size(&keyframes.interpolate(0.0, wrap));
+    let audio = match &options.audio {
+        Some(audio) => {
+            let mapping = AudioMapping::load(&options.audio_map, &Settings::get_default())?;
+            let features =
+                AudioFeatures::analyze(audio, profile.fps, frames, start..end, mapping.decay)?;
+            Some((mapping, features))
+        }
+        None => None,
+    };
+    let mux_audio = if options.mux_audio && whole {
+        options.audio.clone()
+    } else {
+        None
+    };
+    let video_output = if whole {
+        format!("{}/{}.{}", dir, profile.output_name, profile.extension())
+    } else {
+        format!(
+            "{}/{}_{:04}-{:04}.{}",
+            dir,
+            profile.output_name,
+            start,
+            end,
+            profile.extension()
+        )
+    };
     let mut kernel = Kernel::create(device, queue, width, height);
+    kernel.set_denoise(options.denoise);
+    kernel.set_transparent(profile.alpha);
+    kernel.set_download_aovs(options.passes);
     let progress = Progress::new();
 
     let (send, recv) = mpsc::sync_channel(5);
 
-    let thread_handle = match format {
-        VideoFormat::PngSeq => {
-            std::thread::spawn(move || pngseq_write(&recv, false).expect("Couldn't write frame"))
+    let thread_dir = dir.clone();
+    let thread_profile = profile.clone();
+    let thread_handle = match profile.kind {
+        OutputKind::PngSeq => {
+            let encoded = png_encode_pool(recv, options.encode_threads);
+            std::thread::spawn(move || {
+                pngseq_write(&encoded, &thread_dir).expect("Couldn't write frame")
+            })
         }
-        VideoFormat::Gif => {
-            std::thread::spawn(move || pngseq_write(&recv, true).expect("Couldn't write frame"))
+        OutputKind::Gif | OutputKind::Apng => {
+            let writer = AnimationWriter::new(
+                &profile,
+                &video_output,
+                (width, height),
+                end - start,
+                profile.alpha,
+            )?;
+            std::thread::spawn(move || {
+                animation_write(&recv, writer).expect("Couldn't write frame")
+            })
         }
-        VideoFormat::MP4 => {
-            std::thread::spawn(move || video_write(&recv, false).expect("Couldn't write frame"))
+        OutputKind::Ffmpeg => {
+            let encoded = png_encode_pool(recv, options.encode_threads);
+            std::thread::spawn(move || {
+                video_write(&encoded, start, &video_output, &thread_profile, mux_audio)
+                    .expect("Couldn't write frame")
+            })
         }
-        VideoFormat::Twitter => {
-            std::thread::spawn(move || video_write(&recv, true).expect("Couldn't write frame"))
+    };
+
+    // The passes are saved here, the image goes to the writer thread
+    let finish = |kernel: &mut Kernel, frame: usize, download| -> Result<(), Error> {
+        let (image, aovs) = kernel.finish_download_with_aovs(device, download);
+        if let Some(aovs) = aovs {
+            passes::save_passes(&aovs, image.size, &frame_stem(&dir, frame))?;
         }
+        send.send((frame, image))?;
+        Ok(())
     };
 
-    for frame in 0..frames {
-        let settings = keyframes.interpolate(frame as f64 / frames as f64, wrap);
-        video_one(device, queue, rpp, &mut kernel, &settings, &send)?;
-        let value = (frame + 1) as f64 / frames as f64;
+    // With pipelining, frame N is read back and encoded while frame N+1 renders
+    let mut pending = None;
+    let mut rendered = 0;
+    for frame in start..end {
+        let value = (frame + 1 - start) as f64 / (end - start) as f64;
+        if options.resume && Path::new(&frame_path(&dir, frame)).exists() {
+            info!("frame {} already exists, skipping", frame);
+            continue;
+        }
+        let mut settings = keyframes.interpolate(timing.map(frame as f64 / frames as f64), wrap);
+        if let Some((mapping, features)) = &audio {
+            mapping.apply(&mut settings, features, frame);
+        }
+        let download = video_one(device, queue, rpp, &mut kernel, &settings, options.batching);
+        if let Some((previous, download)) = pending.take() {
+            finish(&mut kernel, previous, download)?;
+        }
+        if options.pipeline {
+            pending = Some((frame, download));
+        } else {
+            finish(&mut kernel, frame, download)?;
+        }
+        rendered += 1;
         info!("{}", progress.time_str(value));
     }
+    if let Some((previous, download)) = pending {
+        finish(&mut kernel, previous, download)?;
+    }
     drop(send);
     thread_handle.join().expect("Couldn't join thread");
-    info!("done");
+    let elapsed = progress.elapsed();
+    info!(
+        "done, {} frames in {:.2}s, {:.2} frames/s",
+        rendered,
+        elapsed,
+        rendered as f64 / elapsed
+    );
     Ok(())
 }
 
-fn parse_resolution(res: &str) -> Option<(u32, u32)> {
+// Sizes given as width-height are used as is, named ones are fitted to the scene's projection
+#[derive(Clone, Copy)]
+enum Resolution {
+    Exact(u32, u32),
+    Named(u32, u32),
+}
+
+impl Resolution {
+    fn size(self, settings: &Settings) -> (u32, u32) {
+        match self {
+            Resolution::Exact(width, height) => (width, height),
+            Resolution::Named(width, height) => {
+                kernel_uniforms::projection_size(settings, (width, height))
+            }
+        }
+    }
+}
+
+fn parse_resolution(res: &str) -> Option<Resolution> {
     if let Some(dash) = res.find('-') {
         let (x, y) = res.split_at(dash);
         let y = &y[1..];
-        Some((x.parse().ok()?, y.parse().ok()?))
+        Some(Resolution::Exact(x.parse().ok()?, y.parse().ok()?))
     } else {
-        match res {
-            "32k" => Some((30720, 17280)),
-            "16k" => Some((15360, 8640)),
-            "8k" => Some((7680, 4320)),
-            "4k" => Some((3840, 2160)),
-            "2k" => Some((1920, 1080)),
-            "1k" => Some((960, 540)),
-            "0.5k" => Some((480, 270)),
-            "0.25k" => Some((240, 135)),
-            "twitter" => Some((1280, 720)),
-            _ => None,
-        }
+        let (width, height) = match res {
+            "32k" => (30720, 17280),
+            "16k" => (15360, 8640),
+            "8k" => (7680, 4320),
+            "4k" => (3840, 2160),
+            "2k" => (1920, 1080),
+            "1k" => (960, 540),
+            "0.5k" => (480, 270),
+            "0.25k" => (240, 135),
+            "twitter" => (1280, 720),
+            _ => return None,
+        };
+        Some(Resolution::Named(width, height))
     }
 }
 
 async fn render(args: &[String]) -> Result<(), Error> {
-    if args.len() == 2 {
-        let (width, height) = parse_resolution(&args[0]).ok_or("Invalid resolution")?;
+    if args.len() >= 2 {
+        let resolution = parse_resolution(&args[0]).ok_or("Invalid resolution")?;
         let rpp = args[1].parse()?;
+        let options = RenderOptions::parse(&args[2..])?;
         let (device, queue) = render_window::run_headless().await;
-        image(&device, &queue, width, height, rpp)
+        image(&device, &queue, resolution, rpp, options)
     } else {
-        Err("--render needs two args: [width-height|0.25k..32k|twitter] [rpp]".into())
+        Err(
+            "--render needs two args: [width-height|0.25k..32k|twitter] [rpp], followed by options"
+                .into(),
+        )
     }
 }
 
 async fn video_cmd(args: &[String]) -> Result<(), Error> {
-    if args.len() == 5 {
-        let (width, height) = parse_resolution(&args[0]).ok_or("Invalid resolution")?;
+    if args.len() >= 5 {
+        let resolution = parse_resolution(&args[0]).ok_or("Invalid resolution")?;
         let rpp = args[1].parse()?;
         let frames = args[2].parse()?;
         let wrap = args[3].parse()?;
-        let format = args[4].parse()?;
+        let profile = VideoProfile::find(&args[4], "presets.clam5")?;
+        // the camera path speed can be given before the options, as it was before there were any
+        let (speed, options) = match args.get(5) {
+            Some(speed) if !speed.starts_with("--") => (Some(speed.parse()?), &args[6..]),
+            _ => (None, &args[5..]),
+        };
+        let mut options = VideoOptions::parse(options)?;
+        if let Some(speed) = speed {
+            options.speed = speed;
+        }
         let (device, queue) = render_window::run_headless().await;
-        video(&device, &queue, width, height, rpp, frames, wrap, format)
+        video(
+            &device, &queue, resolution, rpp, frames, wrap, profile, options,
+        )
     } else {
-        Err("--video needs four args: [width-height|0.25k..32k|twitter] [rpp] [frames] [wrap:true|false] [format:mp4|twitter|pngseq|gif]".into())
+        Err("--video needs five args: [width-height|0.25k..32k|twitter] [rpp] [frames] [wrap:true|false] [preset], followed by [speed:keyframe|constant|scaled] and options".into())
     }
 }
 
-fn pngseq(format: VideoFormat) -> Result<(), Error> {
-    match format {
-        VideoFormat::Gif => do_gifize(),
-        VideoFormat::MP4 => video_write_from_pngseq(false),
-        VideoFormat::Twitter => video_write_from_pngseq(true),
-        VideoFormat::PngSeq => Err("--video needs one arg: [format:mp4|twitter|gif]".into()),
+fn pngseq(profile: &VideoProfile, dir: &str) -> Result<(), Error> {
+    match profile.kind {
+        OutputKind::Gif | OutputKind::Apng => animation_write_from_pngseq(dir, profile),
+        OutputKind::Ffmpeg => video_write_from_pngseq(dir, profile),
+        OutputKind::PngSeq => Err("--pngseq needs a gif, apng or video preset".into()),
     }
 }
 
 fn pngseq_cmd(args: &[String]) -> Result<(), Error> {
+    if args.len() == 1 || args.len() == 2 {
+        let profile = VideoProfile::find(&args[0], "presets.clam5")?;
+        let dir = args.get(1).map_or("video", |dir| dir.as_str());
+        pngseq(&profile, dir)
+    } else {
+        Err("--pngseq needs one or two args: [preset] [dir]".into())
+    }
+}
+
+fn tracks_cmd(args: &[String]) -> Result<(), Error> {
     if args.len() == 1 {
-        pngseq(args[0].parse()?)
+        let wrap = args[0].parse()?;
+        let default_settings = Settings::get_default();
+        let keyframes = KeyframeList::load("keyframes.clam5", default_settings.clone())?;
+        let tracks = TrackList::from_keyframes(&keyframes, wrap);
+        tracks.save("tracks.clam5", &default_settings)?;
+        info!("Converted keyframes.clam5 to tracks.clam5");
+        Ok(())
     } else {
-        Err("--video needs one arg: [format:mp4|twitter|gif]".into())
+        Err("--tracks needs one arg: [wrap:true|false]".into())
     }
 }
 
@@ -392,17 +853,56 @@ pub async fn run() -> Result<(), Error> {
         render(&arguments[1..]).await?
     } else if arguments.len() > 2 && &arguments[0] == "--video" {
         video_cmd(&arguments[1..]).await?
-    } else if arguments.len() == 2 && &arguments[0] == "--pngseq" {
+    } else if (arguments.len() == 2 || arguments.len() == 3) && &arguments[0] == "--pngseq" {
         pngseq_cmd(&arguments[1..])?
+    } else if arguments.len() == 2 && &arguments[0] == "--tracks" {
+        tracks_cmd(&arguments[1..])?
     } else if arguments.is_empty() {
         if let Ok(window) = render_window::RenderWindow::new().await {
             window.run()
         }
     } else {
         info!("Usage:");
-        info!("clam5 --render [width-height|0.25k..32k|twitter] [rpp]");
-        info!("clam5 --video [width-height|0.25k..32k|twitter] [rpp] [frames] [wrap:true|false] [format:mp4|twitter|pngseq|gif]");
-        info!("clam5 --pngseq [format:mp4|twitter|gif]");
+        info!("clam5 --render [width-height|0.25k..32k|twitter] [rpp] [options]");
+        info!("  --samples-per-dispatch [n]: samples per pixel per gpu dispatch, default 16");
+        info!("  --time-budget [seconds]: max duration of one dispatch, 0 for none");
+        info!("  --target-noise [x]: adaptive sampling, stop when every pixel's relative error is below x");
+        info!(
+            "  --min-samples [n]: samples before a pixel may be considered converged, default 16"
+        );
+        info!("  --max-time [seconds]: stop after this long, rpp 0 for no sample limit");
+        info!("  --heatmap: also save the estimated noise per pixel");
+        info!("  --denoise: filter the result, guided by albedo, normal and depth");
+        info!("  --passes: also save depth, normal, albedo, color counter and ray step passes");
+        info!("  --transparent: the sky becomes a transparent background");
+        info!("clam5 --video [width-height|0.25k..32k|twitter] [rpp] [frames] [wrap:true|false] [preset] [speed:keyframe|constant|scaled] [options]");
+        info!("  presets: pngseq|gif|apng|mp4|twitter|h264|h265|vp9|av1|prores, or defined in presets.clam5");
+        info!("  --fps, --codec, --crf, --bitrate, --pixel-format, --container, --output, --dither [value]: override preset");
+        info!("  --ffmpeg-args [\"args\"]: extra ffmpeg output args");
+        info!("  --speed [keyframe|constant|scaled]: camera path speed, the same as the positional speed");
+        info!("  --audio [file.wav]: animate settings from audio, mapped by audio.clam5");
+        info!("  --audio-map [file]: audio mapping file to use instead of audio.clam5");
+        info!("  --mux-audio: add the audio to ffmpeg video output");
+        info!("  --start [frame] --end [frame]: only render frames start..end");
+        info!("  --resume: skip frames that already exist (pngseq)");
+        info!("  --out [dir]: output directory, default \"video\"");
+        info!("  --no-pipeline: wait for each frame's download before rendering the next");
+        info!("  --encode-threads [n]: png encoding threads, default one per core");
+        info!("  --denoise: denoise every frame");
+        info!("  --passes: also save the passes of every frame, named after the frame number");
+        info!(
+            "  --transparent: transparent background, for pngseq, gif, apng, or vp9/prores video"
+        );
+        info!("  --samples-per-dispatch, --time-budget: as for --render");
+        info!("named resolutions keep the width for the equirectangular (2:1) and cubemap (3:2)");
+        info!(
+            "  projections, and the height for the fisheye (1:1). Side by side stereo splits the"
+        );
+        info!("  width between the eyes, over-under stereo doubles the height");
+        info!("clam5 --pngseq [preset] [dir]");
+        info!("clam5 --tracks [wrap:true|false]");
+        info!("  writes tracks.clam5, which --video and interactive playback then use instead of");
+        info!("  keyframes.clam5");
         info!("clam5");
     }
     Ok(())
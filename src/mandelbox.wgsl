@@ -7,6 +7,56 @@ var<storage,read_write> randbuf: array<u32>;
 var samp: sampler;
 @group(0) @binding(4) 
 var sky: texture_2d<f32>;
+// Importance sampling distribution of the sky, see `distribution` in sky.rs: width, height, the
+// marginal cdf over rows, then the conditional cdf of each row
+@group(0) @binding(7) 
+var<storage,read> sky_distribution: array<f32>;
+// Gradient for the palette coloring modes, see palette.rs
+@group(0) @binding(8)
+var palette: texture_1d<f32>;
+// Per pixel sample count, and running mean and sum of squared differences (Welford) of the
+// luminance of the samples, used to estimate the remaining noise
+struct PixelStats {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    dummy: u32,
+}
+
+@group(0) @binding(5) 
+var<storage,read_write> stats: array<PixelStats>;
+
+// Feature buffers of the first hit, averaged over the samples. They guide the denoiser and can
+// be exported as separate passes.
+struct Aov {
+    albedo_depth: vec4<f32>,
+    normal: vec4<f32>,
+    // color counter, ray steps
+    counter_steps: vec4<f32>,
+}
+
+@group(0) @binding(6) 
+var<storage,read_write> aovs: array<Aov>;
+
+// See `Light` in kernel_uniforms.rs
+struct Light {
+    pos: vec4<f32>,
+    dir: vec4<f32>,
+    color: vec4<f32>,
+    intensity: f32,
+    radius: f32,
+    angle: f32,
+    kind: u32,
+}
+
+const LIGHT_OFF: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPHERE: u32 = 2u;
+const LIGHT_DIRECTIONAL: u32 = 3u;
+const LIGHT_SPOT: u32 = 4u;
+// a point light without falloff
+const LIGHT_CONSTANT: u32 = 5u;
+const MAX_LIGHTS: u32 = 4u;
 
 struct Data {
     pos: vec4<f32>,
@@ -24,14 +74,27 @@ struct Data {
     fog_distance: f32,
     fog_brightness: f32,
     sky_brightness: f32,
+    sky_rotation: f32,
     surface_color_variance: f32,
     surface_color_shift: f32,
     surface_color_saturation: f32,
     surface_color_value: f32,
     surface_color_gloss: f32,
+    surface_roughness: f32,
+    surface_metallic: f32,
+    surface_roughness_variance: f32,
+    surface_metallic_variance: f32,
+    surface_transmission: f32,
+    surface_ior: f32,
+    absorption_distance: f32,
     plane: vec4<f32>,
-    light_pos: vec4<f32>,
-    light_color: vec4<f32>,
+    lights: array<Light, 4>,
+    sky_tint: vec4<f32>,
+    sun_direction: vec4<f32>,
+    sun_color: vec4<f32>,
+    emission_color: vec4<f32>,
+    trap_point: vec4<f32>,
+    absorption_color: vec4<f32>,
     rotation: f32,
     bailout: f32,
     bailout_normal: f32,
@@ -48,18 +111,32 @@ struct Data {
     max_ray_steps: u32,
     num_ray_bounces: u32,
     gamma_test: u32,
+    emission_mode: u32,
+    emission_intensity: f32,
+    emission_threshold: f32,
+    color_mode: u32,
+    palette_scale: f32,
+    palette_offset: f32,
+    trap_shape: u32,
+    trap_radius: f32,
+    projection: u32,
+    fisheye_angle: f32,
+    equirectangular_angle: f32,
+    stereo: u32,
+    interpupillary_distance: f32,
+    convergence: f32,
     width: u32,
     height: u32,
-    frame: u32,
+    samples_per_dispatch: u32,
+    noise_threshold: f32,
+    min_samples: u32,
+    transparent: u32,
+    seed: u32,
 }
 
 @group(0) @binding(2) 
 var<uniform> data: Data;
 
-fn is_zero(v: vec3<f32>) -> bool {
-    return v.x == 0.0 || v.y == 0.0 || v.z == 0.0;
-}
-
 fn HueToRGB(huep: f32, saturationp: f32, value: f32) -> vec3<f32> {
     var hue = huep;
     hue = hue % 1.0;
@@ -78,23 +155,146 @@ fn HueToRGB(huep: f32, saturationp: f32, value: f32) -> vec3<f32> {
     return color;
 }
 
-fn SampleSphericalMap(v: vec3<f32>) -> vec2<f32> {
-    var invAtan = vec2<f32>(1.0, 2.0) / 6.28318530718;
-    // TODO: hack -v.y
-    var uv = vec2<f32>(atan2(v.z, v.x), asin(-v.y));
-    uv *= invAtan;
-    uv += 0.5;
-    return uv;
+// Equirectangular coordinates of a direction. The first row of the map is straight up (+y), and
+// its center column faces +x before `sky_rotation` is applied.
+fn SampleSphericalMap(dir: vec3<f32>) -> vec2<f32> {
+    let pi = 3.14159265359;
+    let angle = atan2(dir.z, dir.x) - data.sky_rotation;
+    let u = fract(angle / (2.0 * pi) + 0.5);
+    let v = 0.5 - asin(clamp(dir.y, -1.0, 1.0)) / pi;
+    return vec2<f32>(u, v);
 }
 
-fn SampleSky(dir: vec3<f32>) -> vec3<f32> {
+// `lod` is the mip level, 0 is the full resolution map
+fn SampleSkyLod(dir: vec3<f32>, lod: f32) -> vec3<f32> {
     if data.sky_brightness <= 0.0 {
         return vec3<f32>(0.0, 0.0, 0.0);
     }
     let coords = SampleSphericalMap(dir);
-    let dim = textureDimensions(sky);
-    let icoords = vec2<i32>(i32(coords.x * f32(dim.x)), i32(coords.y * f32(dim.y)));
-    return textureLoad(sky, icoords, 0).rgb * data.sky_brightness;
+    return textureSampleLevel(sky, samp, coords, lod).rgb * data.sky_tint.xyz * data.sky_brightness;
+}
+
+fn SampleSky(dir: vec3<f32>) -> vec3<f32> {
+    return SampleSkyLod(dir, 0.0);
+}
+
+// Mip level matching the footprint of a camera ray, so sharp sky features don't alias
+fn PrimarySkyLod(width: u32, height: u32) -> f32 {
+    // angle between neighbouring pixels around the middle of the image, see `Camera`
+    var pixel_angle: f32;
+    switch data.projection {
+        case 1u: {
+            pixel_angle = data.equirectangular_angle / f32(width);
+        }
+        case 2u: {
+            pixel_angle = data.fisheye_angle / f32(min(width, height));
+        }
+        case 3u: {
+            // a face is 2 wide at a distance of 1 and a third of the image
+            pixel_angle = 6.0 / f32(width);
+        }
+        case 4u: {
+            // parallel rays
+            return 0.0;
+        }
+        default: {
+            pixel_angle = 4.0 * data.fov / f32(width + height);
+        }
+    }
+    let texel_angle = 6.28318530718 / f32(textureDimensions(sky).x);
+    return max(log2(pixel_angle / texel_angle), 0.0);
+}
+
+// Index i in 0..count with cdf[i] <= value < cdf[i + 1], the cdf starting at `start`
+fn SearchCdf(start: u32, count: u32, value: f32) -> u32 {
+    var low = 0u;
+    var high = count - 1u;
+    while low < high {
+        let mid = (low + high + 1u) / 2u;
+        if sky_distribution[start + mid] <= value {
+            low = mid;
+        } else {
+            high = mid - 1u;
+        }
+    }
+    return low;
+}
+
+// Solid angle pdf of `SampleSkyDirection` picking `dir`
+fn SkyDirectionPdf(dir: vec3<f32>) -> f32 {
+    let pi = 3.14159265359;
+    let width = u32(sky_distribution[0]);
+    let height = u32(sky_distribution[1]);
+    let coords = SampleSphericalMap(dir);
+    let x = min(u32(coords.x * f32(width)), width - 1u);
+    let y = min(u32(coords.y * f32(height)), height - 1u);
+    let row = sky_distribution[2u + y + 1u] - sky_distribution[2u + y];
+    let conditional = 3u + height + y * (width + 1u);
+    let column = sky_distribution[conditional + x + 1u] - sky_distribution[conditional + x];
+    let sin_theta = sqrt(max(1.0 - dir.y * dir.y, 0.0));
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+    return row * column * f32(width * height) / (2.0 * pi * pi * sin_theta);
+}
+
+struct SkySample {
+    dir: vec3<f32>,
+    pdf: f32,
+}
+
+// Picks a direction with probability proportional to the sky's brightness
+fn SampleSkyDirection(rand: ptr<function, Random>) -> SkySample {
+    let pi = 3.14159265359;
+    let width = u32(sky_distribution[0]);
+    let height = u32(sky_distribution[1]);
+    // Random_Next may return exactly 1
+    let below_one = 0.99999994;
+    let y = SearchCdf(2u, height, min(Random_Next(rand), below_one));
+    let x = SearchCdf(3u + height + y * (width + 1u), width, min(Random_Next(rand), below_one));
+    let u = (f32(x) + Random_Next(rand)) / f32(width);
+    let v = (f32(y) + Random_Next(rand)) / f32(height);
+    // inverse of SampleSphericalMap
+    let angle = (u - 0.5) * 2.0 * pi + data.sky_rotation;
+    let latitude = (0.5 - v) * pi;
+    let dir = vec3<f32>(cos(latitude) * cos(angle), sin(latitude), cos(latitude) * sin(angle));
+    return SkySample(dir, SkyDirectionPdf(dir));
+}
+
+// The sun of the procedural sky, a disk of angular radius `sun_direction.w`. `sun_color` is its
+// irradiance, zero when there is no sun.
+fn SunDirection() -> vec3<f32> {
+    // rotates with the sky, see SampleSphericalMap
+    let dir = normalize(data.sun_direction.xyz);
+    let c = cos(data.sky_rotation);
+    let s = sin(data.sky_rotation);
+    return vec3<f32>(dir.x * c - dir.z * s, dir.y, dir.x * s + dir.z * c);
+}
+
+// 2 pi (1 - cos(radius)), written so small suns don't round to zero
+fn SunSolidAngle() -> f32 {
+    let s = sin(0.5 * data.sun_direction.w);
+    return 4.0 * 3.14159265359 * s * s;
+}
+
+fn HasSun() -> bool {
+    return any(data.sun_color.xyz > vec3<f32>(0.0));
+}
+
+// Radiance of the sun disk towards `dir`
+fn SunRadiance(dir: vec3<f32>) -> vec3<f32> {
+    let sun = SunDirection();
+    // the cross product stays accurate for tiny angles, unlike the dot product
+    if !HasSun() || dot(dir, sun) <= 0.0 || length(cross(dir, sun)) >= sin(data.sun_direction.w) {
+        return vec3<f32>(0.0);
+    }
+    return data.sun_color.xyz / SunSolidAngle();
+}
+
+// Uniform over the sun disk, the pdf is 1 / SunSolidAngle()
+fn SampleSunDirection(rand: ptr<function, Random>) -> vec3<f32> {
+    let s = sin(0.5 * data.sun_direction.w);
+    return SampleCone(SunDirection(), 2.0 * s * s, rand);
 }
 
 struct Random {
@@ -149,8 +349,238 @@ fn Random_Ball(this_: ptr<function, Random>) -> vec3<f32> {
     return pow(Random_Next(this_), 1.0 / 3.0) * Random_Sphere(this_);
 }
 
+// Cosine weighted, the pdf is dot(normal, dir) / pi
 fn Random_Lambertian(this_: ptr<function, Random>, normal: vec3<f32>) -> vec3<f32> {
-    return normalize(Random_Ball(this_) + normal);
+    return normalize(Random_Sphere(this_) + normal);
+}
+
+// `local` with z along `axis`
+fn FromTangentSpace(axis: vec3<f32>, local: vec3<f32>) -> vec3<f32> {
+    let helper = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(axis.x) > 0.9);
+    let tangent = normalize(cross(axis, helper));
+    let bitangent = cross(axis, tangent);
+    return normalize(tangent * local.x + bitangent * local.y + axis * local.z);
+}
+
+// Uniform over the directions within a cone around `axis`, whose solid angle is
+// 2 pi `one_minus_cos`
+fn SampleCone(axis: vec3<f32>, one_minus_cos: f32, rand: ptr<function, Random>) -> vec3<f32> {
+    let t = one_minus_cos * Random_Next(rand);
+    let cos_theta = 1.0 - t;
+    let sin_theta = sqrt(max(t * (2.0 - t), 0.0));
+    let phi = 6.28318530718 * Random_Next(rand);
+    return FromTangentSpace(axis, vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta));
+}
+
+fn Luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Falloff of spot lights towards the edge of their cone, `dir` points away from the light
+fn SpotFactor(light: Light, dir: vec3<f32>) -> f32 {
+    if light.kind != LIGHT_SPOT {
+        return 1.0;
+    }
+    let cos_angle = dot(dir, normalize(light.dir.xyz));
+    return smoothstep(cos(light.angle), cos(light.angle * 0.8), cos_angle);
+}
+
+// Rough irradiance of a light at `pos`, for picking which light to sample
+fn LightImportance(light: Light, pos: vec3<f32>) -> f32 {
+    let power = Luminance(light.color.xyz) * light.intensity;
+    if light.kind == LIGHT_OFF || light.kind > LIGHT_CONSTANT || !(power > 0.0) {
+        return 0.0;
+    }
+    if light.kind == LIGHT_DIRECTIONAL || light.kind == LIGHT_CONSTANT {
+        return power;
+    }
+    let to_light = light.pos.xyz - pos;
+    var distance2 = max(dot(to_light, to_light), 1e-6);
+    if light.kind == LIGHT_SPHERE {
+        distance2 = max(distance2, light.radius * light.radius);
+    }
+    return power / distance2 * SpotFactor(light, -normalize(to_light));
+}
+
+struct LightSample {
+    // towards the light
+    dir: vec3<f32>,
+    distance: f32,
+    // arriving along `dir` at normal incidence, divided by the pdf of picking `dir`
+    irradiance: vec3<f32>,
+}
+
+fn SampleLight(light: Light, pos: vec3<f32>, rand: ptr<function, Random>) -> LightSample {
+    let pi = 3.14159265359;
+    let intensity = light.color.xyz * light.intensity;
+    if light.kind == LIGHT_DIRECTIONAL {
+        var dir = -normalize(light.dir.xyz);
+        if light.radius > 0.0 {
+            // a disk like the sun, uniform radiance over the cone gives the same irradiance
+            let s = sin(0.5 * min(light.radius, pi));
+            dir = SampleCone(dir, 2.0 * s * s, rand);
+        }
+        return LightSample(dir, data.max_ray_dist, intensity);
+    }
+    let to_center = light.pos.xyz - pos;
+    let distance = max(length(to_center), 1e-6);
+    let axis = to_center / distance;
+    if light.kind == LIGHT_SPHERE && light.radius > 0.0 && distance > light.radius {
+        // uniform over the cone the sphere covers, with the radiance that makes it as bright as
+        // a point light of the same intensity from afar
+        let sin_max2 = light.radius * light.radius / (distance * distance);
+        let one_minus_cos = sin_max2 / (1.0 + sqrt(1.0 - sin_max2));
+        let dir = SampleCone(axis, one_minus_cos, rand);
+        let along = dot(dir, to_center);
+        let hit = along - sqrt(max(light.radius * light.radius - (distance * distance - along * along), 0.0));
+        let radiance = intensity / (pi * light.radius * light.radius);
+        return LightSample(dir, hit, radiance * 2.0 * pi * one_minus_cos);
+    }
+    if light.kind == LIGHT_CONSTANT {
+        return LightSample(axis, distance, intensity);
+    }
+    return LightSample(axis, distance, intensity / (distance * distance) * SpotFactor(light, -axis));
+}
+
+// Next event estimation for the scene's lights: picks one in proportion to its rough
+// contribution and casts a shadow ray to it. The irradiance is divided by the chance of picking
+// the light, zero when shadowed.
+fn SampleLights(pos: vec3<f32>, quality: f32, rand: ptr<function, Random>) -> LightSample {
+    var importance = array<f32, MAX_LIGHTS>();
+    var total = 0.0;
+    for (var i = 0u; i < MAX_LIGHTS; i++) {
+        importance[i] = LightImportance(data.lights[i], pos);
+        total += importance[i];
+    }
+    if total <= 0.0 {
+        return LightSample(vec3<f32>(0.0, 1.0, 0.0), 0.0, vec3<f32>(0.0));
+    }
+    var pick = Random_Next(rand) * total;
+    var index = 0u;
+    for (; index + 1u < MAX_LIGHTS; index++) {
+        if importance[index] > 0.0 && pick < importance[index] {
+            break;
+        }
+        pick -= importance[index];
+    }
+    // rounding can leave the pick past the last light that has any importance
+    while importance[index] <= 0.0 {
+        index -= 1u;
+    }
+    var light_sample = SampleLight(data.lights[index], pos, rand);
+    light_sample.irradiance *= total / importance[index];
+    if Cast(Ray(pos, light_sample.dir), quality, light_sample.distance) < light_sample.distance {
+        light_sample.irradiance = vec3<f32>(0.0);
+    } else if data.fog_distance != 0.0 {
+        // chance of reaching the light without scattering in fog
+        light_sample.irradiance *= exp(-light_sample.distance / data.fog_distance);
+    }
+    return light_sample;
+}
+
+// Below this GGX alpha the specular lobe is treated as a perfect mirror
+const MIN_GGX_ALPHA: f32 = 1e-4;
+
+fn FresnelSchlick(f0: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(1.0 - clamp(cos_theta, 0.0, 1.0), 5.0);
+}
+
+// GGX normal distribution, alpha is the squared roughness
+fn GgxD(cos_half: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = cos_half * cos_half * (a2 - 1.0) + 1.0;
+    return a2 / (3.14159265359 * d * d);
+}
+
+// Smith masking for one direction
+fn SmithG1(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    return 2.0 * cos_theta / (cos_theta + sqrt(a2 + (1.0 - a2) * cos_theta * cos_theta));
+}
+
+// Microfacet specular brdf, `view` and `light` point away from the surface
+fn GgxBrdf(normal: vec3<f32>, view: vec3<f32>, light: vec3<f32>, f0: vec3<f32>, alpha: f32) -> vec3<f32> {
+    let cos_view = max(dot(normal, view), 1e-4);
+    let cos_light = max(dot(normal, light), 1e-4);
+    let half_vector = normalize(view + light);
+    let d = GgxD(max(dot(normal, half_vector), 0.0), alpha);
+    let g = SmithG1(cos_view, alpha) * SmithG1(cos_light, alpha);
+    return FresnelSchlick(f0, dot(view, half_vector)) * d * g / (4.0 * cos_view * cos_light);
+}
+
+// Half vector distributed by GgxD times its cosine to the normal
+fn SampleGgx(normal: vec3<f32>, alpha: f32, rand: ptr<function, Random>) -> vec3<f32> {
+    let u = Random_Next(rand);
+    let cos_theta = sqrt((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u));
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 6.28318530718 * Random_Next(rand);
+    return FromTangentSpace(normal, vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta));
+}
+
+// Reflectance of an unpolarized ray at a smooth boundary, `eta` is the ratio of the index of
+// refraction it comes from to the one it enters
+fn DielectricFresnel(cos_i: f32, eta: f32) -> f32 {
+    let sin_t2 = eta * eta * (1.0 - cos_i * cos_i);
+    if sin_t2 >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = sqrt(1.0 - sin_t2);
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (rs * rs + rp * rp);
+}
+
+// The surface is denser than the outside, an index below 1 would make rays entering it totally
+// reflect, which `refract` gives as a zero direction
+fn SurfaceIor() -> f32 {
+    return max(data.surface_ior, 1.0);
+}
+
+// Internal reflections followed before a refracted path is given up
+const MAX_INTERNAL_BOUNCES: u32 = 8u;
+
+struct Refraction {
+    pos: vec3<f32>,
+    dir: vec3<f32>,
+    // Beer-Lambert absorption along the way, zero when the ray never got out
+    transmittance: vec3<f32>,
+}
+
+// Follows a ray refracted into the surface at `pos` until it leaves again, with Fresnel and
+// total internal reflection at the inner side of the surface
+fn Refract(pos: vec3<f32>, dir: vec3<f32>, tolerance: f32, rand: ptr<function, Random>) -> Refraction {
+    let absorption = -log(max(data.absorption_color.xyz, vec3<f32>(1e-4))) / max(data.absorption_distance, 1e-6);
+    var result = Refraction(pos, dir, vec3<f32>(1.0));
+    for (var bounce = 0u; bounce < MAX_INTERNAL_BOUNCES; bounce++) {
+        let travelled = CastInside(Ray(result.pos, result.dir), tolerance, data.max_ray_dist);
+        result.transmittance *= exp(-absorption * travelled);
+        if travelled >= data.max_ray_dist {
+            break;
+        }
+        result.pos = result.pos + result.dir * travelled;
+        // facing back inside, against the ray
+        let normal = -GetMaterial(result.pos).normal;
+        let cos_i = max(-dot(result.dir, normal), 0.0);
+        let ior = SurfaceIor();
+        let fresnel = DielectricFresnel(cos_i, ior);
+        let refracted = refract(result.dir, normal, ior);
+        // the zero vector is total internal reflection, where the fresnel term is 1 as well
+        if Random_Next(rand) < fresnel || all(refracted == vec3<f32>(0.0)) {
+            result.dir = reflect(result.dir, normal);
+            // back to the inside of the surface
+            result.pos += normal * tolerance;
+        } else {
+            result.dir = refracted;
+            return result;
+        }
+    }
+    result.transmittance = vec3<f32>(0.0);
+    return result;
+}
+
+// Multiple importance sampling weight of a strategy with pdf `a` against one with pdf `b`
+fn PowerHeuristic(a: f32, b: f32) -> f32 {
+    return a * a / max(a * a + b * b, 1e-30);
 }
 
 struct Ray {
@@ -189,7 +619,8 @@ fn Ray_Dof(this_: ptr<function, Ray>, focalPlane: f32, rand: ptr<function, Rando
     (*this_).org = focalPosition - (*this_).dir * focalPlane;
 }
 
-fn Camera(x: u32, y: u32, width: u32, height: u32, rand: ptr<function, Random>) -> Ray {
+// `eye` is -1 for the left eye, 1 for the right, and 0 without stereo
+fn Camera(x: u32, y: u32, width: u32, height: u32, eye: f32, rand: ptr<function, Random>) -> Ray {
 // #ifdef NOANTIALIAS
 //     vec2 antialias = vec2(0, 0);
 // #else
@@ -197,12 +628,145 @@ fn Camera(x: u32, y: u32, width: u32, height: u32, rand: ptr<function, Random>)
 // #endif
     let screenCoords = vec2<f32>(f32(x) - f32(width) / 2.0, f32(y) - f32(height) / 2.0) + antialias;
     let calcFov = data.fov * 2.0 / f32(width + height);
-    let direction = RayDir(data.look.xyz, data.up.xyz, screenCoords, calcFov);
-    var result = Ray(data.pos.xyz, direction);
+    let forward = data.look.xyz;
+    let up = data.up.xyz;
+    let right = cross(forward, up);
+    var origin = data.pos.xyz;
+    var direction: vec3<f32>;
+    switch data.projection {
+        case 1u: {
+            let uv = (vec2<f32>(f32(x), f32(y)) + 0.5 + antialias) / vec2<f32>(f32(width), f32(height));
+            let pi = 3.14159265359;
+            let longitude = (uv.x - 0.5) * data.equirectangular_angle;
+            let latitude = (uv.y - 0.5) * pi;
+            direction = (forward * cos(longitude) + right * sin(longitude)) * cos(latitude) + up * sin(latitude);
+        }
+        case 2u: {
+            let p = screenCoords / (f32(min(width, height)) / 2.0);
+            let r = length(p);
+            if r > 1.0 {
+                // outside the circle, no ray
+                return Ray(origin, vec3<f32>(0.0));
+            }
+            let theta = r * data.fisheye_angle / 2.0;
+            let phi = atan2(p.y, p.x);
+            direction = forward * cos(theta) + (right * cos(phi) + up * sin(phi)) * sin(theta);
+        }
+        case 3u: {
+            let faceSize = vec2<f32>(f32(width) / 3.0, f32(height) / 2.0);
+            let pixel = vec2<f32>(f32(x), f32(y)) + 0.5 + antialias;
+            let column = min(u32(pixel.x / faceSize.x), 2u);
+            let row = min(u32(pixel.y / faceSize.y), 1u);
+            let local = (pixel - vec2<f32>(f32(column), f32(row)) * faceSize) / faceSize * 2.0 - 1.0;
+            // the first row is the bottom one
+            var faceForward: vec3<f32>;
+            var faceRight: vec3<f32>;
+            var faceUp = up;
+            switch select(3u, 0u, row == 1u) + column {
+                case 0u: {
+                    faceForward = right;
+                    faceRight = -forward;
+                }
+                case 1u: {
+                    faceForward = -right;
+                    faceRight = forward;
+                }
+                case 2u: {
+                    faceForward = up;
+                    faceRight = right;
+                    faceUp = -forward;
+                }
+                case 3u: {
+                    faceForward = -up;
+                    faceRight = right;
+                    faceUp = forward;
+                }
+                case 4u: {
+                    faceForward = forward;
+                    faceRight = right;
+                }
+                default: {
+                    faceForward = -forward;
+                    faceRight = -right;
+                }
+            }
+            direction = normalize(faceForward + faceRight * local.x + faceUp * local.y);
+        }
+        case 4u: {
+            // the same size as the perspective image at the focal plane
+            origin += (right * screenCoords.x + up * screenCoords.y) * calcFov * 2.0 * data.focal_distance;
+            direction = forward;
+        }
+        default: {
+            direction = RayDir(forward, up, screenCoords, calcFov);
+        }
+    }
+    if eye != 0.0 {
+        // Around the wide projections the eyes follow each ray's horizontal right, so they stay
+        // level in every direction. It shrinks towards the poles, where there is no right.
+        var offset = right;
+        if data.projection >= 1u && data.projection <= 3u {
+            offset = cross(direction, up);
+        }
+        let converge = origin + direction * data.convergence;
+        origin += offset * eye * data.interpupillary_distance / 2.0;
+        direction = normalize(converge - origin);
+    }
+    var result = Ray(origin, direction);
     Ray_Dof(&result, data.focal_distance, rand);
     return result;
 }
 
+// A pixel within the image of one eye
+struct EyePixel {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    eye: f32,
+}
+
+fn StereoPixel(x: u32, y: u32) -> EyePixel {
+    switch data.stereo {
+        case 1u: {
+            let width = data.width / 2u;
+            if x < width {
+                return EyePixel(x, y, width, data.height, -1.0);
+            }
+            return EyePixel(x - width, y, width, data.height, 1.0);
+        }
+        case 2u: {
+            // the left eye is on top, and the first row is the bottom one
+            let height = data.height / 2u;
+            if y >= height {
+                return EyePixel(x, y - height, data.width, height, -1.0);
+            }
+            return EyePixel(x, y, data.width, height, 1.0);
+        }
+        default: {
+            return EyePixel(x, y, data.width, data.height, 0.0);
+        }
+    }
+}
+
+// Seeded by the position within the eye's image, the sample number and the image's seed, so both
+// eyes get the same random numbers and their noise and depth of field match, and the noise still
+// changes from one image to the next
+fn EyeRand(pixel: EyePixel, sample: u32) -> Random {
+    var rand = Random((sample * 2654435769u) ^ (data.seed * 2246822519u));
+    Random_Init(&rand, pixel.y * pixel.width + pixel.x);
+    return rand;
+}
+
+fn TracePixel(pixel: EyePixel, eye: f32, rand: ptr<function, Random>, first_hit: ptr<function, FirstHit>) -> vec3<f32> {
+    let ray = Camera(pixel.x, pixel.y, pixel.width, pixel.height, eye, rand);
+    // pixels the projection doesn't cover stay black, and transparent
+    if all(ray.dir == vec3<f32>(0.0)) {
+        return vec3<f32>(0.0);
+    }
+    return Trace(ray, pixel.width, pixel.height, rand, first_hit);
+}
+
 fn Mandelbulb(z: ptr<function, vec3<f32>>, dz: ptr<function, f32>, power: f32) {
     let zz = *z;
     let r = length(zz.xyz);
@@ -279,6 +843,33 @@ fn DeSphere(org: vec3<f32>, radius: f32, test: vec3<f32>) -> f32 {
     return length(test - org) - radius;
 }
 
+// Distance of an orbit point to the orbit trap shape
+fn TrapDistance(z: vec3<f32>) -> f32 {
+    switch data.trap_shape {
+        case 1u: {
+            return abs(Plane(z, data.plane.xyz));
+        }
+        case 2u: {
+            return abs(length(z - data.trap_point.xyz) - data.trap_radius);
+        }
+        default: {
+            return length(z - data.trap_point.xyz);
+        }
+    }
+}
+
+// Statistics of the orbit of the last `DeMandelbox` with `isNormal` set, for coloring
+struct Orbit {
+    // closest the orbit came to the orbit trap
+    trap: f32,
+    // continuous escape iteration, the iteration limit when it doesn't escape
+    iterations: f32,
+    // box folded components and sphere folds, summed over the iterations
+    folds: f32,
+}
+
+var<private> orbit: Orbit;
+
 fn DeMandelbox(offset: vec3<f32>, isNormal: bool, color: ptr<function, u32>) -> f32 {
     var z = vec3<f32>(offset.x, offset.y, offset.z);
     var dz = 1.0f;
@@ -286,16 +877,33 @@ fn DeMandelbox(offset: vec3<f32>, isNormal: bool, color: ptr<function, u32>) ->
     var bail: f32;
     if isNormal {
         bail = data.bailout_normal;
+        orbit = Orbit(1E+37, 0.0, 0.0);
     } else {
         bail = data.bailout;
     }
     loop {
-        Mandelbox(&z, &dz, offset, color);
+        // marching only needs the distance
+        if isNormal {
+            let folded = abs(z) > vec3<f32>(data.folding_limit);
+            orbit.folds += dot(select(vec3<f32>(0.0), vec3<f32>(1.0), folded), vec3<f32>(1.0));
+            let old_color = *color;
+            Mandelbox(&z, &dz, offset, color);
+            orbit.folds += select(0.0, 1.0, *color != old_color);
+            orbit.trap = min(orbit.trap, TrapDistance(z));
+            orbit.iterations += 1.0;
+        } else {
+            Mandelbox(&z, &dz, offset, color);
+        }
         n = n - 1u;
         if dot(z, z) > bail * bail || n == 0u {
             break;
         }
     }
+    if isNormal && dot(z, z) > bail * bail {
+        // each iteration scales the orbit by about `scale`
+        let escape = log(log(length(z)) / log(bail)) / log(max(abs(data.scale), 1.0001));
+        orbit.iterations = max(orbit.iterations + 1.0 - escape, 0.0);
+    }
     return length(z) / dz;
 }
 
@@ -341,19 +949,75 @@ struct Material {
     color: vec3<f32>,
     normal: vec3<f32>,
     emissive: vec3<f32>,
+    // specular reflectance at normal incidence of the non-metallic part
     gloss: f32,
+    roughness: f32,
+    metallic: f32,
+    counter: u32,
 };
 
+// Linear between the palette's texels, wrapping around
+fn SamplePalette(t: f32) -> vec3<f32> {
+    let width = textureDimensions(palette);
+    let x = fract(t) * f32(width) - 0.5;
+    let left = floor(x);
+    let a = u32(i32(left) + i32(width)) % width;
+    let b = (a + 1u) % width;
+    return mix(textureLoad(palette, a, 0).rgb, textureLoad(palette, b, 0).rgb, x - left);
+}
+
+// Albedo from the color counter and orbit of the `DeFractal` call that produced `counter`
+fn SurfaceColor(counter: u32) -> vec3<f32> {
+    var value: f32;
+    switch data.color_mode {
+        case 1u: {
+            value = orbit.trap;
+        }
+        case 2u: {
+            value = orbit.iterations;
+        }
+        case 3u: {
+            value = orbit.folds / max(orbit.iterations, 1.0);
+        }
+        default: {
+            let hue = f32(counter) * data.surface_color_variance + data.surface_color_shift;
+            return HueToRGB(hue, data.surface_color_saturation, data.surface_color_value);
+        }
+    }
+    return SamplePalette(value * data.palette_scale + data.palette_offset);
+}
+
+// Glow of the surface, from the color counter and orbit trap of the `DeFractal` call that
+// produced `counter`
+fn Emission(counter: u32) -> vec3<f32> {
+    var amount = 0.0;
+    switch data.emission_mode {
+        case 1u: {
+            // the mandelbox counts down, so the counter is signed
+            amount = clamp(f32(bitcast<i32>(counter)) - data.emission_threshold, 0.0, 1.0);
+        }
+        case 2u: {
+            amount = 1.0 - smoothstep(0.0, data.emission_threshold, orbit.trap);
+        }
+        default: {}
+    }
+    return data.emission_color.xyz * data.emission_intensity * amount;
+}
+
 fn GetMaterial(offset: vec3<f32>) -> Material {
     var raw_color_data = 0u;
     let de = DeFractal(offset, true, &raw_color_data);
 
-    let hue = f32(raw_color_data) * data.surface_color_variance + data.surface_color_shift;
-    let color = HueToRGB(hue, data.surface_color_saturation, data.surface_color_value);
     var result: Material;
-    result.color = color;
+    result.color = SurfaceColor(raw_color_data);
     result.gloss = data.surface_color_gloss;
-    result.emissive = vec3<f32>(0.0, 0.0, 0.0);
+    // the counter picks a pseudo random offset per region, golden ratio steps keep neighbouring
+    // counts apart
+    let region = fract(f32(raw_color_data) * 0.618034) * 2.0 - 1.0;
+    result.roughness = clamp(data.surface_roughness + data.surface_roughness_variance * region, 0.0, 1.0);
+    result.metallic = clamp(data.surface_metallic + data.surface_metallic_variance * region, 0.0, 1.0);
+    result.emissive = Emission(raw_color_data);
+    result.counter = raw_color_data;
 
     let delta = max(1e-6f, de * 0.5f); // aprox. 8.3x float epsilon
 // #ifdef CUBE_NORMAL
@@ -381,13 +1045,18 @@ fn GetMaterial(offset: vec3<f32>) -> Material {
     return result;
 }
 
+// Number of distance estimates taken by the last `Cast`
+var<private> cast_steps: u32;
+
 fn Cast(ray: Ray, quality: f32, maxDist: f32) -> f32 {
     var distance: f32;
     var totalDistance = 0.0f;
     var i = max(data.max_ray_steps, 1u);
+    cast_steps = 0u;
     loop {
         distance = De(Ray_At(ray, totalDistance), false) * data.de_multiplier;
         totalDistance += distance;
+        cast_steps++;
         i = i - 1u;
         if totalDistance > maxDist || distance * quality < totalDistance || i == 0u {
             break;
@@ -400,16 +1069,55 @@ fn Cast(ray: Ray, quality: f32, maxDist: f32) -> f32 {
         for (var correctStep = 0; correctStep < 4; correctStep++) {
             distance = De(Ray_At(ray, totalDistance), false) * data.de_multiplier;
             totalDistance += distance - totalDistance / quality;
+            cast_steps++;
         }
     }
     return totalDistance;
 }
 
-fn Trace(rayp: Ray, width: u32, height: u32, rand: ptr<function, Random>) -> vec3<f32> {
+// Marches from just inside the surface until the ray leaves it, returning the distance to a
+// point just outside, or `maxDist`. The distance estimate is about zero everywhere inside, and
+// the surface is where it reaches `tolerance`, so the estimate minus `tolerance` is a signed
+// distance that's negative inside. Its negation is how far the ray can go before it could
+// cross the surface, which is small near thin walls and `tolerance` deep inside.
+fn CastInside(ray: Ray, tolerance: f32, maxDist: f32) -> f32 {
+    var totalDistance = tolerance;
+    var i = max(data.max_ray_steps, 1u);
+    loop {
+        cast_steps++;
+        let distance = De(Ray_At(ray, totalDistance), false) * data.de_multiplier - tolerance;
+        if distance > 0.0 {
+            break;
+        }
+        // a floor keeps it from creeping up on the surface forever
+        totalDistance += max(-distance, tolerance / 16.0);
+        i = i - 1u;
+        if totalDistance > maxDist || i == 0u {
+            return maxDist;
+        }
+    }
+    return totalDistance;
+}
+
+struct FirstHit {
+    albedo: vec3<f32>,
+    normal: vec3<f32>,
+    depth: f32,
+    // the raw color counter from `DeFractal`, as signed since the mandelbox counts down
+    counter: f32,
+    steps: f32,
+    // 0 when the camera ray escaped to the sky
+    coverage: f32,
+}
+
+fn Trace(rayp: Ray, width: u32, height: u32, rand: ptr<function, Random>, first_hit: ptr<function, FirstHit>) -> vec3<f32> {
     var ray = rayp;
     var rayColor = vec3<f32>(0.0, 0.0, 0.0);
     var reflectionColor = vec3<f32>(1.0, 1.0, 1.0);
     var quality = data.quality_first_ray * (f32(width + height) / (2.0 * data.fov));
+    // pdf of the last diffuse bounce's direction, 0 when the sky and sun weren't sampled at its
+    // origin
+    var bouncePdf = 0.0;
 
     for (var photonIndex = 0u; photonIndex < data.num_ray_bounces; photonIndex++) {
         var fog_dist: f32;
@@ -420,47 +1128,164 @@ fn Trace(rayp: Ray, width: u32, height: u32, rand: ptr<function, Random>) -> vec
         }
         let max_dist = min(data.max_ray_dist, fog_dist);
         let distance = min(Cast(ray, quality, max_dist), fog_dist);
+        let steps = f32(cast_steps);
 
 
         if distance >= data.max_ray_dist || (photonIndex + 1u == data.num_ray_bounces && distance >= fog_dist) {
              // went out-of-bounds, or last fog ray didn't hit anything
-            let color = SampleSky(ray.dir);
-            rayColor += color * reflectionColor;
+            let color = SampleSkyLod(ray.dir, select(0.0, PrimarySkyLod(width, height), photonIndex == 0u));
+            if photonIndex == 0u {
+                *first_hit = FirstHit(color, -ray.dir, data.max_ray_dist, 0.0, steps, 0.0);
+                if data.transparent != 0u {
+                    // leave the background to compositing
+                    break;
+                }
+            }
+            var weight = 1.0;
+            var sun_weight = 1.0;
+            // only a real escape could also have been found by next event estimation
+            if bouncePdf > 0.0 && distance >= data.max_ray_dist {
+                weight = PowerHeuristic(bouncePdf, SkyDirectionPdf(ray.dir));
+                sun_weight = PowerHeuristic(bouncePdf, 1.0 / SunSolidAngle());
+            }
+            rayColor += (color * weight + SunRadiance(ray.dir) * sun_weight) * reflectionColor;
             break;
         }
 
-        let newPos = Ray_At(ray, min(distance, fog_dist));
+        var newPos = Ray_At(ray, min(distance, fog_dist));
         var newDir: vec3<f32>;
 
-        let to_light = data.light_pos.xyz - newPos;
-        let distance_to_light = length(to_light);
-        let lit = select(vec3<f32>(0.0, 0.0, 0.0), data.light_color.xyz, !is_zero(data.light_color.xyz) && Cast(Ray(newPos, normalize(to_light)), quality, distance_to_light) >= distance_to_light);
-
         if distance >= fog_dist {
              // hit fog, do fog calculations
+            if photonIndex == 0u {
+                *first_hit = FirstHit(vec3<f32>(data.fog_brightness), -ray.dir, distance, 0.0, steps, 1.0);
+            }
             newDir = Random_Sphere(rand);
+            bouncePdf = 0.0;
             reflectionColor *= data.fog_brightness;
-            rayColor += reflectionColor * lit;
+            let light_sample = SampleLights(newPos, quality, rand);
+            // isotropic phase function
+            rayColor += reflectionColor * light_sample.irradiance / (4.0 * 3.14159265359);
         } else {
              // hit surface, do material calculations
             let material = GetMaterial(newPos);
+            if photonIndex == 0u {
+                *first_hit = FirstHit(material.color, material.normal, distance, f32(bitcast<i32>(material.counter)), steps, 1.0);
+            }
             rayColor += reflectionColor * material.emissive; // ~bling~!
-            rayColor += reflectionColor * max(dot(material.normal, to_light), 0.0) * lit;
-            if Random_Next(rand) < material.gloss {
-                newDir = ray.dir;
+            let pi = 3.14159265359;
+            let view = -ray.dir;
+            let cos_view = max(dot(material.normal, view), 1e-4);
+            let alpha = material.roughness * material.roughness;
+            // transmissive surfaces reflect like a dielectric of their index of refraction
+            let ior = SurfaceIor();
+            let ior_f0 = pow((ior - 1.0) / (ior + 1.0), 2.0);
+            let dielectric_f0 = mix(material.gloss, ior_f0, data.surface_transmission);
+            let f0 = mix(vec3<f32>(dielectric_f0), material.color, material.metallic);
+            // what the specular lobe doesn't reflect is split between refraction and diffuse,
+            // metals have neither
+            let fresnel = FresnelSchlick(f0, cos_view);
+            let unreflected = (1.0 - material.metallic) * (vec3<f32>(1.0) - fresnel);
+            let transmitted = unreflected * data.surface_transmission;
+            let diffuse = material.color * unreflected * (1.0 - data.surface_transmission);
+
+            // bounces can't find the scene's lights, so both lobes see them here
+            let light_sample = SampleLights(newPos, quality, rand);
+            let cos_light = max(dot(material.normal, light_sample.dir), 0.0);
+            if cos_light > 0.0 {
+                // same diffuse response as the bounce: cos * color per cosine weighted sample
+                var response = diffuse * cos_light * cos_light / pi;
+                if alpha >= MIN_GGX_ALPHA {
+                    response += GgxBrdf(material.normal, view, light_sample.dir, f0, alpha) * cos_light;
+                }
+                rayColor += reflectionColor * response * light_sample.irradiance;
+            }
+
+            let specular_weight = Luminance(fresnel);
+            let transmission_weight = Luminance(transmitted);
+            let diffuse_weight = Luminance(diffuse);
+            let total_weight = max(specular_weight + transmission_weight + diffuse_weight, 1e-6);
+            let specular_chance = specular_weight / total_weight;
+            let transmission_chance = transmission_weight / total_weight;
+            let lobe = Random_Next(rand);
+            if lobe < specular_chance {
                  // specular
-                if dot(ray.dir, material.normal) < 0.0 {
-                    newDir -= 2.0f * dot(ray.dir, material.normal) * material.normal;
+                bouncePdf = 0.0;
+                if alpha < MIN_GGX_ALPHA {
+                    newDir = reflect(ray.dir, material.normal);
+                    reflectionColor *= fresnel / specular_chance;
+                } else {
+                    let half_vector = SampleGgx(material.normal, alpha, rand);
+                    newDir = reflect(ray.dir, half_vector);
+                    let cos_new = dot(material.normal, newDir);
+                    let cos_half = max(dot(material.normal, half_vector), 1e-4);
+                    let view_half = max(dot(view, half_vector), 0.0);
+                    if cos_new <= 0.0 {
+                        // reflected into the surface
+                        break;
+                    }
+                    // the brdf times cos over the pdf of sampling the half vector's distribution
+                    let shadowing = SmithG1(cos_view, alpha) * SmithG1(cos_new, alpha);
+                    let weight = FresnelSchlick(f0, view_half) * shadowing * view_half / (cos_view * cos_half);
+                    reflectionColor *= weight / specular_chance;
                 }
-                 // material.color = vec3(1.0, 1.0, 1.0);
+            } else if lobe < specular_chance + transmission_chance {
+                 // refraction, through the inside and out again
+                bouncePdf = 0.0;
+                let entered = refract(ray.dir, material.normal, 1.0 / SurfaceIor());
+                // the surface is as thick as the detail around the focus
+                let tolerance = max(distance, data.focal_distance) / data.quality_rest_ray;
+                let refraction = Refract(newPos, entered, tolerance, rand);
+                newPos = refraction.pos;
+                newDir = refraction.dir;
+                reflectionColor *= transmitted / transmission_chance * refraction.transmittance;
             } else {
                  // diffuse
-                newDir = Random_Lambertian(rand, material.normal);
                 quality = data.quality_rest_ray;
+                let albedo = diffuse / max(1.0 - specular_chance - transmission_chance, 1e-6);
+                if photonIndex + 1u < data.num_ray_bounces && data.sky_brightness > 0.0 {
+                    // next event estimation: sample the sky directly, weighted against finding it
+                    // with the bounce below
+                    let sky_sample = SampleSkyDirection(rand);
+                    let cos_sky = dot(material.normal, sky_sample.dir);
+                    if cos_sky > 0.0 && sky_sample.pdf > 0.0 && Cast(Ray(newPos, sky_sample.dir), quality, data.max_ray_dist) >= data.max_ray_dist {
+                        let pdf = cos_sky / pi;
+                        // chance that the bounce reaches the sky without scattering in fog
+                        var transmittance = 1.0;
+                        if data.fog_distance != 0.0 {
+                            transmittance = exp(-data.max_ray_dist / data.fog_distance);
+                        }
+                        // same response as the bounce: cos * color per cosine weighted sample
+                        let response = albedo * cos_sky * pdf / sky_sample.pdf;
+                        let weight = PowerHeuristic(sky_sample.pdf, pdf);
+                        rayColor += reflectionColor * response * SampleSky(sky_sample.dir) * weight * transmittance;
+                    }
+                }
+                if photonIndex + 1u < data.num_ray_bounces && HasSun() {
+                    // the same for the sun, whose size softens the shadows
+                    let sun_dir = SampleSunDirection(rand);
+                    let cos_sun = dot(material.normal, sun_dir);
+                    if cos_sun > 0.0 && Cast(Ray(newPos, sun_dir), quality, data.max_ray_dist) >= data.max_ray_dist {
+                        let pdf = cos_sun / pi;
+                        let sun_pdf = 1.0 / SunSolidAngle();
+                        var transmittance = 1.0;
+                        if data.fog_distance != 0.0 {
+                            transmittance = exp(-data.max_ray_dist / data.fog_distance);
+                        }
+                        // the disk's radiance over the pdf is its irradiance
+                        let response = albedo * cos_sun * pdf;
+                        let weight = PowerHeuristic(sun_pdf, pdf);
+                        rayColor += reflectionColor * response * data.sun_color.xyz * weight * transmittance;
+                    }
+                }
+                newDir = Random_Lambertian(rand, material.normal);
                 let incident_angle_weakening = dot(material.normal, newDir);
-                reflectionColor *= incident_angle_weakening;
+                bouncePdf = max(incident_angle_weakening, 0.0) / pi;
+                if photonIndex + 1u >= data.num_ray_bounces || (data.sky_brightness <= 0.0 && !HasSun()) {
+                    bouncePdf = 0.0;
+                }
+                reflectionColor *= incident_angle_weakening * albedo;
             }
-            reflectionColor *= material.color;
         }
 
         ray = Ray(newPos, newDir);
@@ -500,12 +1325,24 @@ fn GammaTest(x: u32, y: u32, width: u32, height: u32) -> vec3<f32> {
     return vec3(result, result, result);
 }
 
-fn GetImg(x: u32, y: u32) -> vec3<f32> {
-    return img[y * data.width + x].xyz;
+// Color premultiplied by alpha, alpha is the coverage of camera rays when transparent
+fn GetImg(x: u32, y: u32) -> vec4<f32> {
+    return img[y * data.width + x];
 }
 
-fn SetImg(x: u32, y: u32, value: vec3<f32>) {
-    img[y * data.width + x] = vec4<f32>(value, 0.0);
+fn SetImg(x: u32, y: u32, value: vec4<f32>) {
+    img[y * data.width + x] = value;
+}
+
+// Relative standard error of the pixel mean, dark pixels are judged against a floor of 0.1 so
+// they don't need excessive samples
+fn NoiseEstimate(pixel: PixelStats) -> f32 {
+    if pixel.count < 2u {
+        return 1e30;
+    }
+    let n = f32(pixel.count);
+    let variance = pixel.m2 / (n - 1.0);
+    return sqrt(variance / n) / (pixel.mean + 0.1);
 }
 
 fn GetRand(x: u32, y: u32, invocation_id: u32) -> Random {
@@ -531,24 +1368,65 @@ fn main(@builtin(global_invocation_id) global_invocation_id: vec3<u32>, @builtin
     let x = idx % data.width;
     let y = idx / data.width;
 
-    var newColor: vec3<f32>;
+    var newColor: vec4<f32>;
     if data.gamma_test != 0u {
-        newColor = GammaTest(x, y, data.width, data.height);
+        newColor = vec4<f32>(GammaTest(x, y, data.width, data.height), 1.0);
     } else {
-        var oldColor: vec3<f32>;
-        if data.frame > 0u {
+        var pixel = stats[idx];
+        if data.noise_threshold > 0.0 && pixel.count >= data.min_samples && NoiseEstimate(pixel) < data.noise_threshold {
+            // converged, spend the samples elsewhere
+            return;
+        }
+        let count = pixel.count;
+        var oldColor: vec4<f32>;
+        if count > 0u {
             oldColor = GetImg(x, y);
         } else {
-            oldColor = vec3<f32>(0.0);
+            oldColor = vec4<f32>(0.0);
+        }
+
+        var aov = aovs[idx];
+        if count == 0u {
+            aov = Aov(vec4<f32>(0.0), vec4<f32>(0.0), vec4<f32>(0.0));
         }
 
         var rand = GetRand(x, y, idx);
-        let ray = Camera(x, y, data.width, data.height, &rand);
-        // #ifdef PREVIEW
-        //     vec3 colorComponents = PreviewTrace(ray, width, height);
-        // #else
-        let colorComponents = Trace(ray, data.width, data.height, &rand);
-        newColor = (colorComponents + oldColor * f32(data.frame)) / vec3<f32>(f32(data.frame + 1u));
+        let eyePixel = StereoPixel(x, y);
+        var colorComponents = vec4<f32>(0.0);
+        for (var i = 0u; i < data.samples_per_dispatch; i++) {
+            if data.stereo != 0u {
+                rand = EyeRand(eyePixel, pixel.count);
+            }
+            // #ifdef PREVIEW
+            //     vec3 colorComponents = PreviewTrace(ray, width, height);
+            // #else
+            var first_hit = FirstHit(vec3<f32>(0.0), vec3<f32>(0.0), 0.0, 0.0, 0.0, 0.0);
+            var sample: vec3<f32>;
+            if data.stereo == 3u {
+                // red from the left eye, green and blue from the right
+                var rightRand = rand;
+                var rightHit = first_hit;
+                let left = TracePixel(eyePixel, -1.0, &rand, &first_hit);
+                let right = TracePixel(eyePixel, 1.0, &rightRand, &rightHit);
+                sample = vec3<f32>(left.x, right.y, right.z);
+                first_hit.coverage = max(first_hit.coverage, rightHit.coverage);
+            } else {
+                sample = TracePixel(eyePixel, eyePixel.eye, &rand, &first_hit);
+            }
+            colorComponents += vec4<f32>(sample, select(1.0, first_hit.coverage, data.transparent != 0u));
+            let luminance = dot(sample, vec3<f32>(0.2126, 0.7152, 0.0722));
+            pixel.count += 1u;
+            let delta = luminance - pixel.mean;
+            pixel.mean += delta / f32(pixel.count);
+            pixel.m2 += delta * (luminance - pixel.mean);
+            let weight = 1.0 / f32(pixel.count);
+            aov.albedo_depth += (vec4<f32>(first_hit.albedo, first_hit.depth) - aov.albedo_depth) * weight;
+            aov.normal += (vec4<f32>(first_hit.normal, 0.0) - aov.normal) * weight;
+            aov.counter_steps += (vec4<f32>(first_hit.counter, first_hit.steps, 0.0, 0.0) - aov.counter_steps) * weight;
+        }
+        aovs[idx] = aov;
+        newColor = (colorComponents + oldColor * f32(count)) / f32(pixel.count);
+        stats[idx] = pixel;
         SetRand(x, y, rand);
     }
     SetImg(x, y, newColor);
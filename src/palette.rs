@@ -0,0 +1,153 @@
+use crate::{sky::f16_bits, Error};
+use log::warn;
+
+// Dark blue through orange to pale yellow and back, so it loops when the coloring wraps around
+pub const DEFAULT_PALETTE: &str =
+    "0 0.02 0.03 0.1, 0.35 0.8 0.3 0.05, 0.65 1 0.9 0.55, 1 0.02 0.03 0.1";
+
+// Texels of the gradient texture, the shader interpolates between them
+const PALETTE_WIDTH: u32 = 256;
+
+struct Stop {
+    position: f64,
+    color: [f64; 3],
+}
+
+// Color stops as `position r g b`, separated by commas, with positions in 0..1 and linear colors
+fn parse_stops(text: &str) -> Result<Vec<Stop>, Error> {
+    let mut stops = Vec::new();
+    for stop in text.split(',') {
+        let values = stop
+            .split_whitespace()
+            .map(|value| value.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match values[..] {
+            [position, r, g, b] => stops.push(Stop {
+                position,
+                color: [r, g, b],
+            }),
+            _ => return Err(format!("Palette stop must be 'position r g b': {}", stop).into()),
+        }
+    }
+    if stops.is_empty() {
+        return Err("Palette has no stops".into());
+    }
+    stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+    Ok(stops)
+}
+
+// Linear between stops, constant past the first and last
+fn evaluate(stops: &[Stop], position: f64) -> [f64; 3] {
+    let next = stops.iter().position(|stop| stop.position > position);
+    match next {
+        None => stops.last().unwrap().color,
+        Some(0) => stops[0].color,
+        Some(next) => {
+            let (a, b) = (&stops[next - 1], &stops[next]);
+            let t = (position - a.position) / (b.position - a.position);
+            [0, 1, 2].map(|c| a.color[c] + (b.color[c] - a.color[c]) * t)
+        }
+    }
+}
+
+// Gradient for the palette coloring modes, from the `palette` setting
+pub struct Palette {
+    pub texture: wgpu::Texture,
+    text: Option<String>,
+}
+
+impl Palette {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: PALETTE_WIDTH,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D1,
+            format: wgpu::TextureFormat::Rgba16Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        Self {
+            texture,
+            text: None,
+        }
+    }
+
+    // Rewrites the texture when the stops changed. Invalid stops fall back to the default
+    // palette.
+    pub fn set(&mut self, queue: &wgpu::Queue, text: &str) {
+        if self.text.as_deref() == Some(text) {
+            return;
+        }
+        let stops = parse_stops(text).unwrap_or_else(|err| {
+            warn!("Invalid palette, using the default: {}", err);
+            parse_stops(DEFAULT_PALETTE).unwrap()
+        });
+        let contents = (0..PALETTE_WIDTH)
+            .flat_map(|x| {
+                let [r, g, b] = evaluate(&stops, (x as f64 + 0.5) / PALETTE_WIDTH as f64);
+                [r, g, b, 1.0]
+            })
+            .flat_map(|value| f16_bits(value as f32).to_le_bytes())
+            .collect::<Vec<u8>>();
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &contents,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(PALETTE_WIDTH * 8),
+                rows_per_image: Some(1),
+            },
+            wgpu::Extent3d {
+                width: PALETTE_WIDTH,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.text = Some(text.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_are_sorted() {
+        let stops = parse_stops("1 1 1 1, 0 0 0 0,0.5  0.25 0.5 1").unwrap();
+        let positions: Vec<f64> = stops.iter().map(|stop| stop.position).collect();
+        assert_eq!(positions, [0.0, 0.5, 1.0]);
+        assert_eq!(stops[1].color, [0.25, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn invalid_stops() {
+        assert!(parse_stops("").is_err());
+        assert!(parse_stops("0 1 1").is_err());
+        assert!(parse_stops("0 1 1 1,").is_err());
+        assert!(parse_stops("0 1 x 1").is_err());
+        assert!(parse_stops(DEFAULT_PALETTE).is_ok());
+    }
+
+    #[test]
+    fn evaluate_between_and_past_stops() {
+        let stops = parse_stops("0.25 0 0 0, 0.75 1 0.5 0").unwrap();
+        assert_eq!(evaluate(&stops, 0.0), [0.0, 0.0, 0.0]);
+        assert_eq!(evaluate(&stops, 0.5), [0.5, 0.25, 0.0]);
+        assert_eq!(evaluate(&stops, 0.75), [1.0, 0.5, 0.0]);
+        assert_eq!(evaluate(&stops, 1.0), [1.0, 0.5, 0.0]);
+        let single = parse_stops("0.5 1 2 3").unwrap();
+        assert_eq!(evaluate(&single, 0.0), [1.0, 2.0, 3.0]);
+        assert_eq!(evaluate(&single, 1.0), [1.0, 2.0, 3.0]);
+    }
+}
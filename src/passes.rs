@@ -0,0 +1,74 @@
+use crate::{kernel::PixelAov, Error};
+use png::{BitDepth, ColorType, Encoder};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+// 16 bit png, `value` gives the channels of a pixel in 0..1
+fn save_png16<const N: usize>(
+    path: &str,
+    aovs: &[PixelAov],
+    size: (u32, u32),
+    value: impl Fn(&PixelAov) -> [f32; N],
+) -> Result<(), Error> {
+    let mut encoder = Encoder::new(BufWriter::new(File::create(path)?), size.0, size.1);
+    encoder.set_color(if N == 1 {
+        ColorType::Grayscale
+    } else {
+        ColorType::Rgb
+    });
+    encoder.set_depth(BitDepth::Sixteen);
+    let mut writer = encoder.write_header()?;
+    let mut data = Vec::with_capacity(aovs.len() * N * 2);
+    // the kernel's first row is the bottom one
+    for row in aovs.chunks_exact(size.0 as usize).rev() {
+        for pixel in row {
+            for channel in value(pixel) {
+                let sample = (channel.clamp(0.0, 1.0) * 65535.0).round() as u16;
+                data.extend_from_slice(&sample.to_be_bytes());
+            }
+        }
+    }
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+// Grayscale portable float map, stored bottom row first like the kernel's image
+fn save_pfm(
+    path: &str,
+    aovs: &[PixelAov],
+    size: (u32, u32),
+    value: impl Fn(&PixelAov) -> f32,
+) -> Result<(), Error> {
+    let mut w = BufWriter::new(File::create(path)?);
+    // negative scale means little endian
+    write!(w, "Pf\n{} {}\n-1.0\n", size.0, size.1)?;
+    for pixel in aovs {
+        w.write_all(&value(pixel).to_le_bytes())?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// Writes depth, normal, albedo, color counter and ray step passes next to a beauty image, as
+// `{stem}_{pass}.{png|pfm}`. Unbounded values are written as floats.
+pub fn save_passes(aovs: &[PixelAov], size: (u32, u32), stem: &str) -> Result<(), Error> {
+    save_pfm(&format!("{}_depth.pfm", stem), aovs, size, |pixel| {
+        pixel.depth
+    })?;
+    save_png16(&format!("{}_normal.png", stem), aovs, size, |pixel| {
+        pixel.normal.map(|x| x * 0.5 + 0.5)
+    })?;
+    save_png16(&format!("{}_albedo.png", stem), aovs, size, |pixel| {
+        pixel.albedo
+    })?;
+    save_pfm(&format!("{}_counter.pfm", stem), aovs, size, |pixel| {
+        pixel.counter
+    })?;
+    // one step per unit, up to 65535
+    save_png16(&format!("{}_steps.png", stem), aovs, size, |pixel| {
+        [pixel.steps / 65535.0]
+    })?;
+    Ok(())
+}
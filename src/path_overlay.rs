@@ -0,0 +1,274 @@
+use crate::{
+    cast_slice,
+    kernel_uniforms::{STEREO_OVER_UNDER, STEREO_SIDE_BY_SIDE},
+    keyframe_list::KeyframeList,
+    settings::Settings,
+    track_list::TrackList,
+};
+use cgmath::{InnerSpace, Vector3};
+use wgpu::util::DeviceExt;
+
+const PATH_SAMPLES_PER_KEYFRAME: usize = 32;
+const MARKER_SIZE: f32 = 6.0;
+
+const PATH_COLOR: [f32; 3] = [1.0, 0.8, 0.1];
+const KEYFRAME_COLOR: [f32; 3] = [1.0, 0.2, 0.2];
+const LOOK_COLOR: [f32; 3] = [0.2, 0.8, 1.0];
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Vertex {
+    position: [f32; 2],
+    color: [f32; 3],
+}
+
+struct Keyframe {
+    pos: Vector3<f64>,
+    look: Vector3<f64>,
+    length: f64,
+}
+
+// Draws the camera path of the keyframes, or of tracks.clam5 when it's loaded, on top of the rendered image, projected with the same
+// stereographic mapping the kernel uses in `RayDir`. Other projections and images split into
+// two eyes don't get an overlay.
+pub struct PathOverlay {
+    pipeline: wgpu::RenderPipeline,
+    cached_path: Option<(KeyframeList, Option<TrackList>)>,
+    path: Vec<Vector3<f64>>,
+    keyframes: Vec<Keyframe>,
+}
+
+struct Projection {
+    pos: Vector3<f64>,
+    look: Vector3<f64>,
+    up: Vector3<f64>,
+    right: Vector3<f64>,
+    fov: f64,
+    size: (f64, f64),
+}
+
+impl Projection {
+    fn new(settings: &Settings, size: (u32, u32)) -> Self {
+        let look = settings.find("look").unwrap_vec3();
+        let up = settings.find("up").unwrap_vec3();
+        Self {
+            pos: settings.find("pos").unwrap_vec3(),
+            look,
+            up,
+            right: Vector3::cross(look, up),
+            fov: settings.find("fov").unwrap_float(),
+            size: (size.0 as f64, size.1 as f64),
+        }
+    }
+
+    // Inverse of `RayDir`, returns clip space coordinates
+    fn project(&self, point: Vector3<f64>) -> Option<[f32; 2]> {
+        let dir = point - self.pos;
+        if dir.magnitude2() == 0.0 {
+            return None;
+        }
+        let dir = dir.normalize();
+        let z = dir.dot(self.look);
+        if z <= 0.0 {
+            return None;
+        }
+        let (width, height) = self.size;
+        let calc_fov = self.fov * 2.0 / (width + height);
+        let x = dir.dot(self.right) / ((1.0 + z) * calc_fov);
+        let y = dir.dot(self.up) / ((1.0 + z) * calc_fov);
+        Some([(2.0 * x / width) as f32, (2.0 * y / height) as f32])
+    }
+}
+
+impl PathOverlay {
+    pub fn new(device: &wgpu::Device, target_format: wgpu::TextureFormat) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+
+        let module = device.create_shader_module(wgpu::include_wgsl!("path_overlay.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: "vert",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x3],
+                }],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+                unclipped_depth: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: "frag",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        Self {
+            pipeline,
+            cached_path: None,
+            path: Vec::new(),
+            keyframes: Vec::new(),
+        }
+    }
+
+    fn update_path(&mut self, keyframes: &KeyframeList, tracks: Option<&TrackList>) {
+        if let Some((cached_keyframes, cached_tracks)) = &self.cached_path {
+            if cached_keyframes == keyframes && cached_tracks.as_ref() == tracks {
+                return;
+            }
+        }
+        self.cached_path = Some((keyframes.clone(), tracks.cloned()));
+        self.path.clear();
+        self.keyframes.clear();
+        match tracks {
+            // Markers go where the camera position has a key
+            Some(tracks) => {
+                self.sample_path(tracks.segments(), |time| tracks.interpolate(time, false));
+                for time in tracks.key_times("pos") {
+                    self.push_keyframe(&tracks.interpolate(time, false));
+                }
+            }
+            None => {
+                if keyframes.len() == 0 {
+                    return;
+                }
+                self.sample_path(keyframes.len() - 1, |time| {
+                    keyframes.interpolate(time, false)
+                });
+                for index in 0..keyframes.len() {
+                    self.push_keyframe(keyframes.get(index).unwrap());
+                }
+            }
+        }
+    }
+
+    fn sample_path(&mut self, segments: usize, interpolate: impl Fn(f64) -> Settings) {
+        let count = segments * PATH_SAMPLES_PER_KEYFRAME;
+        for i in 0..=count {
+            let time = if count == 0 {
+                0.0
+            } else {
+                i as f64 / count as f64
+            };
+            let settings = interpolate(time);
+            self.path.push(settings.find("pos").unwrap_vec3());
+        }
+    }
+
+    fn push_keyframe(&mut self, keyframe: &Settings) {
+        self.keyframes.push(Keyframe {
+            pos: keyframe.find("pos").unwrap_vec3(),
+            look: keyframe.find("look").unwrap_vec3().normalize(),
+            length: keyframe.find("focal_distance").unwrap_float() * 0.5,
+        });
+    }
+
+    fn line(vertices: &mut Vec<Vertex>, a: [f32; 2], b: [f32; 2], color: [f32; 3]) {
+        vertices.push(Vertex { position: a, color });
+        vertices.push(Vertex { position: b, color });
+    }
+
+    fn vertices(&self, settings: &Settings, size: (u32, u32)) -> Vec<Vertex> {
+        let projection = Projection::new(settings, size);
+        let mut vertices = Vec::new();
+        for pair in self.path.windows(2) {
+            if let (Some(a), Some(b)) = (projection.project(pair[0]), projection.project(pair[1])) {
+                Self::line(&mut vertices, a, b, PATH_COLOR);
+            }
+        }
+        let marker_x = 2.0 * MARKER_SIZE / size.0 as f32;
+        let marker_y = 2.0 * MARKER_SIZE / size.1 as f32;
+        for keyframe in &self.keyframes {
+            let center = match projection.project(keyframe.pos) {
+                Some(center) => center,
+                None => continue,
+            };
+            let [x, y] = center;
+            Self::line(
+                &mut vertices,
+                [x - marker_x, y - marker_y],
+                [x + marker_x, y + marker_y],
+                KEYFRAME_COLOR,
+            );
+            Self::line(
+                &mut vertices,
+                [x - marker_x, y + marker_y],
+                [x + marker_x, y - marker_y],
+                KEYFRAME_COLOR,
+            );
+            if let Some(end) = projection.project(keyframe.pos + keyframe.look * keyframe.length) {
+                Self::line(&mut vertices, center, end, LOOK_COLOR);
+            }
+        }
+        vertices
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        dst: &wgpu::TextureView,
+        keyframes: &KeyframeList,
+        tracks: Option<&TrackList>,
+        settings: &Settings,
+        size: (u32, u32),
+    ) {
+        let stereo = settings.find("stereo").unwrap_u32() as u32;
+        if settings.find("projection").unwrap_u32() != 0
+            || stereo == STEREO_SIDE_BY_SIDE
+            || stereo == STEREO_OVER_UNDER
+        {
+            return;
+        }
+        self.update_path(keyframes, tracks);
+        let vertices = self.vertices(settings, size);
+        if vertices.is_empty() {
+            return;
+        }
+        let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: dst,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_vertex_buffer(0, vertex_buf.slice(..));
+        render_pass.draw(0..vertices.len() as u32, 0..1);
+    }
+}
@@ -0,0 +1,14 @@
+struct VertexOutput {
+    @location(0) color: vec3<f32>,
+    @builtin(position) position: vec4<f32>,
+}
+
+@vertex
+fn vert(@location(0) position: vec2<f32>, @location(1) color: vec3<f32>) -> VertexOutput {
+    return VertexOutput(color, vec4<f32>(position, 0.0, 1.0));
+}
+
+@fragment
+fn frag(@location(0) color: vec3<f32>) -> @location(0) vec4<f32> {
+    return vec4<f32>(color, 1.0);
+}
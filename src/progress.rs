@@ -11,7 +11,7 @@ impl Progress {
         }
     }
 
-    fn elapsed(&self) -> f64 {
+    pub fn elapsed(&self) -> f64 {
         let now = Instant::now();
         let duration = now - self.start;
         duration.as_secs_f64()
@@ -4,7 +4,10 @@ use std::sync::Arc;
 #[cfg(target_arch = "wasm32")]
 use winit::platform::web::WindowExtWebSys;
 
-use crate::{buffer_blit::BufferBlit, fps_counter::FpsCounter, interactive::SyncInteractiveKernel};
+use crate::{
+    buffer_blit::BufferBlit, fps_counter::FpsCounter, interactive::SyncInteractiveKernel,
+    path_overlay::PathOverlay,
+};
 use winit::{
     event::*,
     event_loop::EventLoop,
@@ -42,6 +45,7 @@ pub struct RenderWindow {
     glyph: wgpu_text::TextBrush,
     size: winit::dpi::PhysicalSize<u32>,
     buffer_blit: BufferBlit,
+    path_overlay: PathOverlay,
     fps_counter: FpsCounter,
     interactive: SyncInteractiveKernel,
 }
@@ -168,6 +172,8 @@ impl RenderWindow {
             !format!("{:?}", swapchain_format.add_srgb_suffix()).contains("Srgb"),
         );
 
+        let path_overlay = PathOverlay::new(&device, swapchain_format.add_srgb_suffix());
+
         #[cfg(target_arch = "wasm32")]
         let font = wgpu_text::glyph_brush::ab_glyph::FontArc::try_from_slice(include_bytes!(
             "C:\\Windows\\Fonts\\arial.ttf"
@@ -196,6 +202,7 @@ impl RenderWindow {
             glyph,
             size,
             buffer_blit,
+            path_overlay,
             fps_counter: FpsCounter::new(1.0),
             interactive,
         })
@@ -260,7 +267,8 @@ impl RenderWindow {
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
-        self.interactive.run(&self.device, &mut encoder);
+        self.interactive
+            .run(&self.device, &self.queue, &mut encoder);
 
         self.buffer_blit.set_src(
             &self.device,
@@ -271,6 +279,18 @@ impl RenderWindow {
         self.buffer_blit
             .blit(&self.device, &mut encoder, &frame_view);
 
+        if self.interactive.input.show_path {
+            self.path_overlay.draw(
+                &self.device,
+                &mut encoder,
+                &frame_view,
+                &self.interactive.keyframes,
+                self.interactive.input.tracks.as_ref(),
+                &self.interactive.settings,
+                (self.size.width, self.size.height),
+            );
+        }
+
         self.fps_counter.tick();
         let display_text = format!(
             "{} fps\n{}",
@@ -1,4 +1,6 @@
+use crate::{parse_vector3, Error};
 use cgmath::Vector3;
+use std::fmt;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SettingValue {
@@ -12,6 +14,8 @@ pub enum SettingValueEnum {
     Int(u64),
     Float(f64, f64),
     Vec3(Vector3<f64>, f64),
+    // not animatable, keyframes step from one value to the next
+    Text(String),
 }
 
 impl SettingValue {
@@ -39,6 +43,7 @@ impl SettingValue {
         match self.value {
             SettingValueEnum::Float(_, _) => (),
             SettingValueEnum::Vec3(_, _) => (),
+            SettingValueEnum::Text(_) => (),
             SettingValueEnum::Int(ref mut value) => {
                 if increase {
                     *value += 1;
@@ -72,7 +77,7 @@ impl SettingValue {
                     *value += dt * change;
                 }
             }
-            SettingValueEnum::Int(_) => (),
+            SettingValueEnum::Int(_) | SettingValueEnum::Text(_) => (),
         }
     }
 
@@ -82,6 +87,7 @@ impl SettingValue {
                 SettingValueEnum::Int(ref mut v) => *v = 0,
                 SettingValueEnum::Float(ref mut v, _) => *v = 0.0,
                 SettingValueEnum::Vec3(ref mut v, _) => *v = Vector3::new(0.0, 0.0, 0.0),
+                SettingValueEnum::Text(ref mut v) => v.clear(),
             }
         } else {
             self.value = self.default_value.clone();
@@ -109,6 +115,13 @@ impl SettingValue {
         }
     }
 
+    pub fn unwrap_text(&self) -> &str {
+        match self.value {
+            SettingValueEnum::Text(ref value) => value,
+            _ => panic!("unwrap_text not text"),
+        }
+    }
+
     pub fn unwrap_vec3_mut(&mut self) -> &mut Vector3<f64> {
         match self.value {
             SettingValueEnum::Vec3(ref mut value, _) => value,
@@ -117,13 +130,38 @@ impl SettingValue {
     }
 }
 
+impl fmt::Display for SettingValueEnum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValueEnum::Int(v) => write!(f, "{}", v),
+            SettingValueEnum::Float(v, _) => write!(f, "{}", v),
+            SettingValueEnum::Vec3(v, _) => write!(f, "{} {} {}", v.x, v.y, v.z),
+            SettingValueEnum::Text(v) => write!(f, "{}", v),
+        }
+    }
+}
+
 impl SettingValueEnum {
+    // Parses a value of the same kind (and change rate) as `self`
+    pub fn parse_like(&self, value: &str) -> Result<SettingValueEnum, Error> {
+        Ok(match *self {
+            SettingValueEnum::Int(_) => SettingValueEnum::Int(value.parse()?),
+            SettingValueEnum::Float(_, change) => SettingValueEnum::Float(value.parse()?, change),
+            SettingValueEnum::Vec3(_, change) => SettingValueEnum::Vec3(
+                parse_vector3(value).ok_or("invalid vector3 in save file")?,
+                change,
+            ),
+            SettingValueEnum::Text(_) => SettingValueEnum::Text(value.to_string()),
+        })
+    }
+
     pub fn kinds_match(&self, other: &SettingValueEnum) -> bool {
         matches!(
             (self, other),
             (SettingValueEnum::Int(_), SettingValueEnum::Int(_))
                 | (SettingValueEnum::Float(_, _), SettingValueEnum::Float(_, _))
                 | (SettingValueEnum::Vec3(_, _), SettingValueEnum::Vec3(_, _))
+                | (SettingValueEnum::Text(_), SettingValueEnum::Text(_))
         )
     }
 }
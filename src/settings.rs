@@ -1,7 +1,8 @@
 use crate::{
     kernel_uniforms::KernelUniforms,
-    parse_vector3,
+    palette::DEFAULT_PALETTE,
     setting_value::{SettingValue, SettingValueEnum},
+    sky::DEFAULT_SKY_MAP,
     Error,
 };
 use cgmath::{prelude::*, Vector3};
@@ -27,6 +28,42 @@ impl Settings {
             "render_scale".to_string(),
             SettingValueEnum::Int(1),
         ));
+        // equirectangular .hdr environment map, empty for a uniform sky
+        default_settings.values.push(SettingValue::new(
+            "sky_map".to_string(),
+            SettingValueEnum::Text(DEFAULT_SKY_MAP.to_string()),
+        ));
+        // color stops `position r g b`, comma separated, for the palette coloring modes
+        default_settings.values.push(SettingValue::new(
+            "palette".to_string(),
+            SettingValueEnum::Text(DEFAULT_PALETTE.to_string()),
+        ));
+        // 0: sky_map, 1: procedural sky with a sun, from the settings below
+        default_settings.values.push(SettingValue::new(
+            "sky_model".to_string(),
+            SettingValueEnum::Int(0),
+        ));
+        default_settings.values.push(SettingValue::new(
+            "sun_direction".to_string(),
+            SettingValueEnum::Vec3(Vector3::new(0.5, 0.6, 0.4), 0.25),
+        ));
+        // angular radius in radians, larger suns give softer shadows
+        default_settings.values.push(SettingValue::new(
+            "sun_radius".to_string(),
+            SettingValueEnum::Float(0.00465, -0.5),
+        ));
+        default_settings.values.push(SettingValue::new(
+            "sun_brightness".to_string(),
+            SettingValueEnum::Float(10.0, -0.5),
+        ));
+        default_settings.values.push(SettingValue::new(
+            "turbidity".to_string(),
+            SettingValueEnum::Float(3.0, 0.5),
+        ));
+        default_settings.values.push(SettingValue::new(
+            "ground_albedo".to_string(),
+            SettingValueEnum::Vec3(Vector3::new(0.3, 0.3, 0.3), -0.25),
+        ));
         default_settings
     }
 
@@ -66,13 +103,7 @@ impl Settings {
                     continue;
                 }
             }
-            match value.value() {
-                SettingValueEnum::Int(v) => writeln!(writer, "{} = {}", value.key(), v)?,
-                SettingValueEnum::Float(v, _) => writeln!(writer, "{} = {}", value.key(), v)?,
-                SettingValueEnum::Vec3(v, _) => {
-                    writeln!(writer, "{} = {} {} {}", value.key(), v.x, v.y, v.z)?
-                }
-            }
+            writeln!(writer, "{} = {}", value.key(), value.value())?;
         }
         Ok(())
     }
@@ -96,23 +127,18 @@ impl Settings {
             if &line == "---" || line.is_empty() {
                 break;
             }
-            let split = line.rsplitn(2, '=').collect::<Vec<_>>();
-            if split.len() != 2 {
-                return Err(format!("Invalid format in settings file: {}", line).into());
-            }
-            let key = split[1].trim();
-            let new_value = split[0].trim();
-            let reference = reference.find(key);
-            let val_enum = match *reference.value() {
-                SettingValueEnum::Int(_) => SettingValueEnum::Int(new_value.parse()?),
-                SettingValueEnum::Float(_, change) => {
-                    SettingValueEnum::Float(new_value.parse()?, change)
-                }
-                SettingValueEnum::Vec3(_, change) => SettingValueEnum::Vec3(
-                    parse_vector3(new_value).ok_or("invalid vector3 in save file")?,
-                    change,
-                ),
+            // split at the first '=', text values such as paths may contain more
+            let (key, new_value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid format in settings file: {}", line))?;
+            let key = match key.trim() {
+                // from before there was a list of lights
+                "light_pos" => "light1_pos",
+                "light_color" => "light1_color",
+                key => key,
             };
+            let new_value = new_value.trim();
+            let val_enum = reference.find(key).value().parse_like(new_value)?;
             result
                 .values
                 .push(SettingValue::new(key.to_string(), val_enum));
@@ -26,6 +26,9 @@ impl SettingsInput {
                 SettingValueEnum::Float(v, _) => {
                     writeln!(&mut builder, "{} {} = {}", selected, key, v).unwrap()
                 }
+                SettingValueEnum::Text(v) => {
+                    writeln!(&mut builder, "{} {} = {}", selected, key, v).unwrap()
+                }
                 SettingValueEnum::Vec3(v, _) => {
                     let selected = if ind == self.index {
                         match self.component {
@@ -49,6 +52,10 @@ impl SettingsInput {
         builder
     }
 
+    pub fn selected_key<'a>(&self, settings: &'a Settings) -> &'a str {
+        settings.values[self.index].key()
+    }
+
     fn num_components_at_current(&self, settings: &Settings) -> usize {
         match settings.values[self.index].value() {
             SettingValueEnum::Vec3(_, _) => 3,
@@ -0,0 +1,527 @@
+use crate::{cast_slice, settings::Settings, Error};
+use log::warn;
+use std::f32::consts::{FRAC_PI_2, PI};
+use wgpu::util::DeviceExt;
+
+pub const DEFAULT_SKY_MAP: &str = "HDR_029_Sky_Cloudy_Env.hdr";
+
+// Embedded, so the default sky works from any working directory and on the web
+const DEFAULT_SKY_DATA: &[u8] = include_bytes!("../HDR_029_Sky_Cloudy_Env.hdr");
+
+// The importance sampling distribution is built from the first mip level at most this wide
+const DISTRIBUTION_MAX_WIDTH: usize = 512;
+
+// Size of the map the procedural sky is baked into
+const PROCEDURAL_WIDTH: usize = 512;
+const PROCEDURAL_HEIGHT: usize = 256;
+// Preetham luminance is in kcd/m^2, this puts the zenith around 1 with the sun at 45 degrees
+const PROCEDURAL_LUMINANCE_SCALE: f32 = 0.125;
+// Radians below the horizon over which the sky fades out after sunset
+const TWILIGHT: f32 = 0.1;
+// Steps the procedural sky's parameters are rounded to for baking, too small to show in the
+// map. The analytic sun and its irradiance keep the exact values.
+const BAKE_ANGLE_STEP: f32 = 0.25 * PI / 180.0;
+const BAKE_TURBIDITY_STEP: f32 = 0.05;
+const BAKE_ALBEDO_STEP: f32 = 1.0 / 256.0;
+const BAKE_BRIGHTNESS_STEPS_PER_OCTAVE: f32 = 64.0;
+// The sun's angular radius is kept above this so its solid angle stays representable
+pub const MIN_SUN_RADIUS: f64 = 1e-4;
+
+fn load_hdr(path: &str) -> Result<hdrldr::Image, Error> {
+    let result = if path == DEFAULT_SKY_MAP {
+        hdrldr::load(DEFAULT_SKY_DATA)
+    } else {
+        #[cfg(target_arch = "wasm32")]
+        return Err("Only the default sky map is available on the web".into());
+        #[cfg(not(target_arch = "wasm32"))]
+        hdrldr::load(std::io::BufReader::new(std::fs::File::open(path)?))
+    };
+    result.map_err(|err| format!("Invalid hdr file {}: {:?}", path, err).into())
+}
+
+// Round to nearest half float, values beyond the half range are clamped
+pub fn f16_bits(value: f32) -> u16 {
+    let value = if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(-65504.0, 65504.0)
+    };
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // subnormal
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        return sign | ((mantissa + (1 << (shift - 1))) >> shift) as u16;
+    }
+    // a mantissa that rounds up carries into the exponent
+    let rounded = (((exponent as u32) << 10) | (mantissa >> 13)) + ((mantissa >> 12) & 1);
+    sign | rounded as u16
+}
+
+// One level of a mip chain, linear RGB
+struct Level {
+    width: usize,
+    height: usize,
+    data: Vec<[f32; 3]>,
+}
+
+impl Level {
+    // Box filtered half size, odd edges repeat the last row or column
+    fn downsample(&self) -> Level {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(self.width - 1);
+                    let sy = (y * 2 + dy).min(self.height - 1);
+                    let texel = self.data[sy * self.width + sx];
+                    for c in 0..3 {
+                        sum[c] += texel[c] * 0.25;
+                    }
+                }
+                data.push(sum);
+            }
+        }
+        Level {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+// Piecewise constant distribution over the texels of an equirectangular map, proportional to
+// luminance times solid angle. Layout: width, height, the marginal cdf over rows (height + 1),
+// then the conditional cdf of each row (width + 1 each), matching `SampleSkyDirection`.
+fn distribution(level: &Level) -> Vec<f32> {
+    let (width, height) = (level.width, level.height);
+    let mut result = Vec::with_capacity(2 + height + 1 + height * (width + 1));
+    result.extend([width as f32, height as f32]);
+    let mut conditional = Vec::with_capacity(height * (width + 1));
+    let mut marginal = vec![0.0];
+    for y in 0..height {
+        let solid_angle = ((y as f32 + 0.5) / height as f32 * PI).sin();
+        let row = &level.data[y * width..(y + 1) * width];
+        let start = conditional.len();
+        let mut sum = 0.0f64;
+        conditional.push(0.0);
+        for texel in row {
+            let luminance = 0.2126 * texel[0] + 0.7152 * texel[1] + 0.0722 * texel[2];
+            sum += (luminance.max(0.0) * solid_angle) as f64;
+            conditional.push(sum as f32);
+        }
+        for value in &mut conditional[start..] {
+            *value = if sum > 0.0 {
+                (*value as f64 / sum) as f32
+            } else {
+                // a black row is never picked, keep the cdf valid anyway
+                0.0
+            };
+        }
+        marginal.push(marginal.last().unwrap() + sum as f32);
+    }
+    let total = *marginal.last().unwrap();
+    if total > 0.0 {
+        for value in &mut marginal {
+            *value /= total;
+        }
+    } else {
+        // black map, sample uniformly so the pdf stays finite
+        for (y, value) in marginal.iter_mut().enumerate() {
+            *value = y as f32 / height as f32;
+        }
+        for y in 0..height {
+            for x in 0..=width {
+                conditional[y * (width + 1) + x] = x as f32 / width as f32;
+            }
+        }
+    }
+    result.extend(marginal);
+    result.extend(conditional);
+    result
+}
+
+// Perez et al. luminance distribution
+fn perez(coefficients: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+// Cubic in the sun's zenith angle, for the zenith chromaticity fits
+fn cubic(coefficients: [f32; 4], theta: f32) -> f32 {
+    let [a, b, c, d] = coefficients;
+    ((a * theta + b) * theta + c) * theta + d
+}
+
+// "A Practical Analytic Model for Daylight", Preetham, Shirley and Smits. Clear to hazy skies
+// from the sun direction and turbidity, with an analytic sun disk and a flat diffuse ground.
+#[derive(Clone, PartialEq)]
+pub struct ProceduralSky {
+    // towards the sun, normalized, before `sky_rotation` is applied
+    pub sun_direction: [f32; 3],
+    pub turbidity: f32,
+    pub ground_albedo: [f32; 3],
+    // irradiance of the sun at normal incidence above the atmosphere
+    pub sun_brightness: f32,
+}
+
+fn round_to(value: f32, step: f32) -> f32 {
+    (value / step).round() * step
+}
+
+impl ProceduralSky {
+    // The parameters the map is baked with, nearby skies share a bake
+    fn quantized(&self) -> Self {
+        let [x, y, z] = self.sun_direction;
+        let elevation = round_to(y.clamp(-1.0, 1.0).asin(), BAKE_ANGLE_STEP);
+        let azimuth = round_to(z.atan2(x), BAKE_ANGLE_STEP);
+        let sun_brightness = if self.sun_brightness > 0.0 {
+            round_to(
+                self.sun_brightness.log2(),
+                1.0 / BAKE_BRIGHTNESS_STEPS_PER_OCTAVE,
+            )
+            .exp2()
+        } else {
+            0.0
+        };
+        Self {
+            sun_direction: [
+                elevation.cos() * azimuth.cos(),
+                elevation.sin(),
+                elevation.cos() * azimuth.sin(),
+            ],
+            turbidity: round_to(self.turbidity, BAKE_TURBIDITY_STEP),
+            ground_albedo: self.ground_albedo.map(|c| round_to(c, BAKE_ALBEDO_STEP)),
+            sun_brightness,
+        }
+    }
+
+    // Light reaching the ground from the sun, at normal incidence. Rayleigh and aerosol
+    // extinction at 680, 550 and 440nm over the relative air mass (Kasten and Young).
+    pub fn sun_irradiance(&self) -> [f32; 3] {
+        let cos_zenith = self.sun_direction[1];
+        if cos_zenith <= 0.0 {
+            return [0.0; 3];
+        }
+        let zenith_degrees = cos_zenith.acos().to_degrees();
+        let air_mass = 1.0 / (cos_zenith + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+        let aerosol = (0.04608 * self.turbidity - 0.04586).max(0.0);
+        let rayleigh = [0.036, 0.097, 0.236];
+        let wavelength = [0.68f32, 0.55, 0.44];
+        [0, 1, 2].map(|c| {
+            let depth = rayleigh[c] + aerosol * wavelength[c].powf(-1.3);
+            self.sun_brightness * (-air_mass * depth).exp()
+        })
+    }
+
+    // Radiance of the sky towards `dir`, without the sun disk
+    fn sky_radiance(&self, dir: [f32; 3]) -> [f32; 3] {
+        let t = self.turbidity;
+        let sun = self.sun_direction;
+        // the fits only hold for the sun above the horizon, below it the sky fades out
+        let sun_elevation = sun[1].clamp(-1.0, 1.0).asin();
+        let fade = (1.0 + sun_elevation / TWILIGHT).clamp(0.0, 1.0);
+        if fade == 0.0 {
+            return [0.0; 3];
+        }
+        let theta_sun = FRAC_PI_2 - sun_elevation.max(0.0);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0], theta_sun)
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394], theta_sun)
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886], theta_sun);
+        let zenith_y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0], theta_sun)
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516], theta_sun)
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688], theta_sun);
+
+        let coefficients_luminance = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let coefficients_x = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let coefficients_y = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+
+        // the distribution is singular at the horizon
+        let cos_theta = dir[1].max(0.01);
+        let cos_gamma = (dir[0] * sun[0] + dir[1] * sun[1] + dir[2] * sun[2]).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let relative = |coefficients| {
+            perez(coefficients, cos_theta, gamma) / perez(coefficients, 1.0, theta_sun)
+        };
+        let luminance = zenith_luminance.max(0.0) * relative(coefficients_luminance);
+        let x = zenith_x * relative(coefficients_x);
+        let y = zenith_y * relative(coefficients_y);
+
+        // xyY to XYZ to linear sRGB
+        let luminance = luminance * PROCEDURAL_LUMINANCE_SCALE * fade;
+        let big_x = x / y * luminance;
+        let big_z = (1.0 - x - y) / y * luminance;
+        [
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        ]
+        .map(|c| c.max(0.0))
+    }
+
+    // Equirectangular map in the layout of a sky map. The lower half is the ground, lit by the
+    // upper half and the sun.
+    fn bake(&self) -> Level {
+        let (width, height) = (PROCEDURAL_WIDTH, PROCEDURAL_HEIGHT);
+        let mut data = Vec::with_capacity(width * height);
+        let mut irradiance = self
+            .sun_irradiance()
+            .map(|c| c * self.sun_direction[1].max(0.0));
+        for y in 0..height / 2 {
+            // inverse of `SampleSphericalMap`, as in `SampleSkyDirection`
+            let latitude = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+            let solid_angle = (2.0 * PI / width as f32) * (PI / height as f32) * latitude.cos();
+            for x in 0..width {
+                let angle = ((x as f32 + 0.5) / width as f32 - 0.5) * 2.0 * PI;
+                let dir = [
+                    latitude.cos() * angle.cos(),
+                    latitude.sin(),
+                    latitude.cos() * angle.sin(),
+                ];
+                let radiance = self.sky_radiance(dir);
+                for c in 0..3 {
+                    irradiance[c] += radiance[c] * latitude.sin() * solid_angle;
+                }
+                data.push(radiance);
+            }
+        }
+        let ground = [0, 1, 2].map(|c| self.ground_albedo[c].max(0.0) * irradiance[c] / PI);
+        data.resize(width * height, ground);
+        Level {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+// Where the environment comes from
+#[derive(Clone, PartialEq)]
+pub enum SkySource {
+    // path of an equirectangular .hdr map, empty for a uniform sky
+    Map(String),
+    Procedural(ProceduralSky),
+}
+
+impl SkySource {
+    pub fn from_settings(settings: &Settings) -> Self {
+        if settings.find("sky_model").unwrap_u32() == 0 {
+            return SkySource::Map(settings.find("sky_map").unwrap_text().to_string());
+        }
+        let sun = settings.find("sun_direction").unwrap_vec3();
+        let length = (sun.x * sun.x + sun.y * sun.y + sun.z * sun.z).sqrt();
+        let sun_direction = if length > 0.0 {
+            [sun.x, sun.y, sun.z].map(|c| (c / length) as f32)
+        } else {
+            [0.0, 1.0, 0.0]
+        };
+        let ground_albedo = settings.find("ground_albedo").unwrap_vec3();
+        SkySource::Procedural(ProceduralSky {
+            sun_direction,
+            // the fits are made for this range
+            turbidity: settings.find("turbidity").unwrap_float().clamp(1.7, 10.0) as f32,
+            ground_albedo: [ground_albedo.x, ground_albedo.y, ground_albedo.z].map(|c| c as f32),
+            sun_brightness: settings.find("sun_brightness").unwrap_float().max(0.0) as f32,
+        })
+    }
+
+    // What the sky map is made from, equal for skies that would bake the same
+    pub fn bake_key(&self) -> Self {
+        match self {
+            SkySource::Map(path) => SkySource::Map(path.clone()),
+            SkySource::Procedural(sky) => SkySource::Procedural(sky.quantized()),
+        }
+    }
+
+    // Direction and irradiance of the sun, zero for maps which have it baked in
+    pub fn sun(&self) -> ([f32; 3], [f32; 3]) {
+        match self {
+            SkySource::Map(_) => ([0.0, 1.0, 0.0], [0.0; 3]),
+            SkySource::Procedural(sky) => (sky.sun_direction, sky.sun_irradiance()),
+        }
+    }
+}
+
+// An empty path, or one that fails to load, gives a uniform white sky that `sky_tint` and
+// `sky_brightness` still apply to
+fn load_map(path: &str) -> Level {
+    let image = if path.is_empty() {
+        None
+    } else {
+        match load_hdr(path) {
+            Ok(image) => Some(image),
+            Err(err) => {
+                warn!("Couldn't load sky map, using a uniform sky: {}", err);
+                None
+            }
+        }
+    };
+    match image {
+        Some(image) => Level {
+            width: image.width,
+            height: image.height,
+            data: image.data.iter().map(|rgb| [rgb.r, rgb.g, rgb.b]).collect(),
+        },
+        None => Level {
+            width: 1,
+            height: 1,
+            data: vec![[1.0; 3]],
+        },
+    }
+}
+
+// The environment map, with mipmaps for filtered lookups and a distribution for importance
+// sampling
+pub struct Sky {
+    pub texture: wgpu::Texture,
+    pub distribution: wgpu::Buffer,
+}
+
+impl Sky {
+    pub fn load(device: &wgpu::Device, queue: &wgpu::Queue, source: &SkySource) -> Self {
+        let base = match source {
+            SkySource::Map(path) => load_map(path),
+            SkySource::Procedural(sky) => sky.bake(),
+        };
+        Self::from_level(device, queue, base)
+    }
+
+    fn from_level(device: &wgpu::Device, queue: &wgpu::Queue, base: Level) -> Self {
+        let mut levels = vec![base];
+        while levels.last().unwrap().width > 1 || levels.last().unwrap().height > 1 {
+            let next = levels.last().unwrap().downsample();
+            levels.push(next);
+        }
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: levels[0].width as u32,
+                height: levels[0].height as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: levels.len() as u32,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            // half floats are filterable everywhere, full floats need a feature
+            format: wgpu::TextureFormat::Rgba16Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        for (mip_level, level) in levels.iter().enumerate() {
+            let contents = level
+                .data
+                .iter()
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.0])
+                .flat_map(|value| f16_bits(value).to_le_bytes())
+                .collect::<Vec<u8>>();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: mip_level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &contents,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(level.width as u32 * 8),
+                    rows_per_image: Some(level.height as u32),
+                },
+                wgpu::Extent3d {
+                    width: level.width as u32,
+                    height: level.height as u32,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let sampled = levels
+            .iter()
+            .find(|level| level.width <= DISTRIBUTION_MAX_WIDTH)
+            .unwrap();
+        let distribution = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: cast_slice(&distribution(sampled)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        Self {
+            texture,
+            distribution,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f16_exact_values() {
+        assert_eq!(f16_bits(0.0), 0x0000);
+        assert_eq!(f16_bits(-0.0), 0x8000);
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+    }
+
+    #[test]
+    fn f16_clamps() {
+        assert_eq!(f16_bits(1e6), 0x7bff);
+        assert_eq!(f16_bits(-1e6), 0xfbff);
+        assert_eq!(f16_bits(f32::INFINITY), 0x7bff);
+        assert_eq!(f16_bits(f32::NAN), 0x0000);
+    }
+
+    #[test]
+    fn f16_subnormals() {
+        // smallest subnormal and normal
+        assert_eq!(f16_bits(2f32.powi(-24)), 0x0001);
+        assert_eq!(f16_bits(2f32.powi(-14)), 0x0400);
+        assert_eq!(f16_bits(3.0 * 2f32.powi(-24)), 0x0003);
+        assert_eq!(f16_bits(-(2f32.powi(-24))), 0x8001);
+        // too small even for a subnormal
+        assert_eq!(f16_bits(1e-10), 0x0000);
+        assert_eq!(f16_bits(-1e-10), 0x8000);
+    }
+
+    #[test]
+    fn f16_rounding() {
+        // the mantissa rounding up carries into the exponent
+        assert_eq!(f16_bits(2047.9), 0x6800);
+        assert_eq!(f16_bits(1.0 + 0.4 * 2f32.powi(-10)), 0x3c00);
+        assert_eq!(f16_bits(1.0 + 0.6 * 2f32.powi(-10)), 0x3c01);
+    }
+}
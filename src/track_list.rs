@@ -0,0 +1,428 @@
+use crate::{
+    keyframe_list::{interpolate_value, zoom_time, KeyframeList, PathSpeed, PathTiming},
+    setting_value::SettingValueEnum,
+    settings::Settings,
+    Error,
+};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+// First line of a track file, used to tell it apart from a keyframe snapshot file
+const TRACKS_HEADER: &str = "tracks";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackInterpolation {
+    Step,
+    Linear,
+    Smooth,
+}
+
+impl std::str::FromStr for TrackInterpolation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("step") {
+            Ok(TrackInterpolation::Step)
+        } else if s.eq_ignore_ascii_case("linear") {
+            Ok(TrackInterpolation::Linear)
+        } else if s.eq_ignore_ascii_case("smooth") {
+            Ok(TrackInterpolation::Smooth)
+        } else {
+            Err(format!("Invalid track interpolation: {}", s).into())
+        }
+    }
+}
+
+impl std::fmt::Display for TrackInterpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackInterpolation::Step => write!(f, "step"),
+            TrackInterpolation::Linear => write!(f, "linear"),
+            TrackInterpolation::Smooth => write!(f, "smooth"),
+        }
+    }
+}
+
+// The interpolation of a key applies to the segment between it and the next key
+#[derive(Clone, PartialEq)]
+struct TrackKey {
+    time: f64,
+    value: SettingValueEnum,
+    interpolation: TrackInterpolation,
+}
+
+#[derive(Clone, PartialEq)]
+struct Track {
+    key: String,
+    keys: Vec<TrackKey>,
+}
+
+// Animation where every setting has its own list of keys. Settings without a track keep their
+// base value. When tracks.clam5 exists, `--video`, interactive playback and the path overlay
+// follow it instead of keyframes.clam5.
+//
+// File format:
+//   tracks
+//   length = 10
+//   sky_brightness = 0.5
+//   light1_color @ 0 = 1 1 1
+//   light1_color @ 2.5 linear = 1 0.5 0.25
+//   pos @ 0 smooth = 0 0 5
+#[derive(Clone, PartialEq)]
+pub struct TrackList {
+    base: Settings,
+    tracks: Vec<Track>,
+    length: f64,
+}
+
+impl Track {
+    fn clamp(&self, index: isize, wrap: bool) -> usize {
+        let len = self.keys.len();
+        if wrap {
+            index.rem_euclid(len as isize) as usize
+        } else {
+            index.clamp(0, len as isize - 1) as usize
+        }
+    }
+
+    // Returns (index of the key at or before `time`, time of that key, time of the next key)
+    fn segment(&self, time: f64, wrap: bool, length: f64) -> (usize, f64, f64) {
+        let after = self.keys.partition_point(|key| key.time <= time);
+        if after == 0 {
+            if wrap {
+                let last = &self.keys[self.keys.len() - 1];
+                (self.keys.len() - 1, last.time - length, self.keys[0].time)
+            } else {
+                (0, time, time)
+            }
+        } else if after == self.keys.len() {
+            let last = &self.keys[after - 1];
+            if wrap {
+                (after - 1, last.time, self.keys[0].time + length)
+            } else {
+                (after - 1, last.time, last.time)
+            }
+        } else {
+            (after - 1, self.keys[after - 1].time, self.keys[after].time)
+        }
+    }
+
+    fn value_at(&self, time: f64, wrap: bool, length: f64) -> SettingValueEnum {
+        self.value_at_warped(time, wrap, length, |_, _, t| t)
+    }
+
+    fn float_at(&self, time: f64, wrap: bool, length: f64) -> f64 {
+        match self.value_at(time, wrap, length) {
+            SettingValueEnum::Float(value, _) => value,
+            _ => panic!("float_at not float"),
+        }
+    }
+
+    fn value_at_warped(
+        &self,
+        time: f64,
+        wrap: bool,
+        length: f64,
+        warp: impl Fn(f64, f64, f64) -> f64,
+    ) -> SettingValueEnum {
+        let time = if wrap && length > 0.0 {
+            time.rem_euclid(length)
+        } else {
+            time
+        };
+        let (index, start, end) = self.segment(time, wrap, length);
+        let key = &self.keys[index];
+        if end <= start || key.interpolation == TrackInterpolation::Step {
+            return key.value.clone();
+        }
+        let t = warp(start, end, (time - start) / (end - start));
+        let index = index as isize;
+        let prev = &self.keys[self.clamp(index - 1, wrap)].value;
+        let next = &self.keys[self.clamp(index + 1, wrap)].value;
+        let next2 = &self.keys[self.clamp(index + 2, wrap)].value;
+        interpolate_value(
+            prev,
+            &key.value,
+            next,
+            next2,
+            t,
+            key.interpolation == TrackInterpolation::Linear,
+        )
+    }
+}
+
+impl TrackList {
+    pub fn load(file: &str, default_settings: Settings, wrap: bool) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(file)?);
+        let mut lines = reader.lines();
+        let is_tracks = match lines.next() {
+            Some(line) => line?.trim() == TRACKS_HEADER,
+            None => false,
+        };
+        if !is_tracks {
+            let keyframes = KeyframeList::load(file, default_settings)?;
+            return Ok(Self::from_keyframes(&keyframes, wrap));
+        }
+        let mut result = Self {
+            base: default_settings,
+            tracks: Vec::new(),
+            length: 0.0,
+        };
+        let mut length = None;
+        for line in lines {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // split at the first '=', text values such as paths may contain more
+            let (left, new_value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid format in tracks file: {}", line))?;
+            let (left, new_value) = (left.trim(), new_value.trim());
+            if left == "length" {
+                length = Some(new_value.parse()?);
+                continue;
+            }
+            let (key, time) = match left.split_once('@') {
+                Some((key, time)) => (key.trim(), Some(time)),
+                None => (left, None),
+            };
+            let reference = result
+                .base
+                .get(key)
+                .ok_or_else(|| format!("Unknown setting in tracks file: {}", key))?;
+            let value = reference.value().parse_like(new_value)?;
+            let time = match time {
+                Some(time) => time,
+                None => {
+                    result.base.find_mut(key).set_value(value);
+                    continue;
+                }
+            };
+            let mut time_split = time.split_ascii_whitespace();
+            let time = time_split
+                .next()
+                .ok_or_else(|| format!("Missing key time in tracks file: {}", line))?
+                .parse()?;
+            let interpolation = match time_split.next() {
+                Some(interpolation) => interpolation.parse()?,
+                None => TrackInterpolation::Smooth,
+            };
+            result.push_key(
+                key,
+                TrackKey {
+                    time,
+                    value,
+                    interpolation,
+                },
+            );
+        }
+        for track in &mut result.tracks {
+            track.keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        }
+        result.length = match length {
+            Some(length) => length,
+            None => result
+                .tracks
+                .iter()
+                .flat_map(|track| track.keys.last())
+                .map(|key| key.time)
+                .fold(0.0, f64::max),
+        };
+        Ok(result)
+    }
+
+    pub fn save(&self, file: &str, default_settings: &Settings) -> Result<(), Error> {
+        let file = File::create(file)?;
+        let mut writer = BufWriter::new(&file);
+        writeln!(&mut writer, "{}", TRACKS_HEADER)?;
+        writeln!(&mut writer, "length = {}", self.length)?;
+        self.base.write_one(&mut writer, default_settings)?;
+        for track in &self.tracks {
+            for key in &track.keys {
+                writeln!(
+                    &mut writer,
+                    "{} @ {} {} = {}",
+                    track.key, key.time, key.interpolation, key.value
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Keyframe `i` becomes a key at time `i` on every setting that changes between keyframes
+    pub fn from_keyframes(keyframes: &KeyframeList, wrap: bool) -> Self {
+        let base = match keyframes.get(0) {
+            Some(first) => first.clone(),
+            None => Settings::get_default(),
+        };
+        let length = if wrap {
+            keyframes.len()
+        } else {
+            keyframes.len().max(1) - 1
+        };
+        let interpolation = if keyframes.len() <= 2 && !wrap {
+            TrackInterpolation::Linear
+        } else {
+            TrackInterpolation::Smooth
+        };
+        let mut result = Self {
+            base,
+            tracks: Vec::new(),
+            length: length as f64,
+        };
+        for value in &result.base.values {
+            let changes = (1..keyframes.len())
+                .any(|index| keyframes.get(index).unwrap().find(value.key()) != value);
+            if !changes {
+                continue;
+            }
+            let keys = (0..keyframes.len())
+                .map(|index| TrackKey {
+                    time: index as f64,
+                    value: keyframes
+                        .get(index)
+                        .unwrap()
+                        .find(value.key())
+                        .value()
+                        .clone(),
+                    interpolation,
+                })
+                .collect();
+            result.tracks.push(Track {
+                key: value.key().to_string(),
+                keys,
+            });
+        }
+        result
+    }
+
+    fn push_key(&mut self, key: &str, track_key: TrackKey) {
+        match self.tracks.iter_mut().find(|track| track.key == key) {
+            Some(track) => track.keys.push(track_key),
+            None => self.tracks.push(Track {
+                key: key.to_string(),
+                keys: vec![track_key],
+            }),
+        }
+    }
+
+    fn track(&self, key: &str) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.key == key)
+    }
+
+    pub fn interpolate(&self, time: f64, wrap: bool) -> Settings {
+        let time = time * self.length;
+        let focal_distance = self.track("focal_distance");
+        let mut result = self.base.clone();
+        for track in &self.tracks {
+            if track.keys.is_empty() {
+                continue;
+            }
+            let value =
+                if track.key == "pos" {
+                    // Keep the zoom-aware camera path from keyframe interpolation
+                    track.value_at_warped(time, wrap, self.length, |start, end, t| {
+                        match focal_distance {
+                            Some(focal_distance) => zoom_time(
+                                focal_distance.float_at(start, wrap, self.length),
+                                focal_distance.float_at(end, wrap, self.length),
+                                t,
+                            ),
+                            None => t,
+                        }
+                    })
+                } else {
+                    track.value_at(time, wrap, self.length)
+                };
+            result.find_mut(&track.key).set_value(value);
+        }
+        result.normalize();
+        result
+    }
+
+    // Number of whole time units, the equivalent of the gaps between keyframes
+    pub fn segments(&self) -> usize {
+        self.length.ceil().max(1.0) as usize
+    }
+
+    // Times of the keys of one setting, scaled to 0..1 like `interpolate` takes them
+    pub fn key_times(&self, key: &str) -> Vec<f64> {
+        match self.track(key) {
+            Some(track) if self.length > 0.0 => track
+                .keys
+                .iter()
+                .map(|key| key.time / self.length)
+                .collect(),
+            Some(track) if !track.keys.is_empty() => vec![0.0],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn timing(&self, wrap: bool, speed: PathSpeed) -> PathTiming {
+        PathTiming::new(speed, self.segments(), |time| self.interpolate(time, wrap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, text: &str) -> Result<TrackList, Error> {
+        let path = std::env::temp_dir().join(format!("clam5_test_{}.clam5", name));
+        std::fs::write(&path, text).unwrap();
+        let result = TrackList::load(path.to_str().unwrap(), Settings::get_default(), false);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn keys_interpolate() {
+        let tracks = load(
+            "keys",
+            "tracks\nfog_brightness = 0.25\nsky_brightness @ 0 linear = 0\nsky_brightness @ 2 = 1\n",
+        )
+        .unwrap();
+        assert_eq!(tracks.length, 2.0);
+        let middle = tracks.interpolate(0.5, false);
+        assert!((middle.find("sky_brightness").unwrap_float() - 0.5).abs() < 1e-9);
+        assert_eq!(middle.find("fog_brightness").unwrap_float(), 0.25);
+    }
+
+    #[test]
+    fn key_times_scale_to_the_length() {
+        let tracks = load(
+            "key_times",
+            "tracks\nlength = 2.5\npos @ 0 = 0 0 5\npos @ 1 = 0 0 4\npos @ 2.5 = 0 0 3\n",
+        )
+        .unwrap();
+        assert_eq!(tracks.segments(), 3);
+        assert_eq!(tracks.key_times("pos"), vec![0.0, 0.4, 1.0]);
+        assert!(tracks.key_times("look").is_empty());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(load("no_equals", "tracks\nsky_brightness\n").is_err());
+        assert!(load("unknown", "tracks\nnot_a_setting = 1\n").is_err());
+        assert!(load("bad_time", "tracks\nsky_brightness @ soon = 1\n").is_err());
+    }
+
+    #[test]
+    fn text_values_keep_equals_signs() {
+        let tracks = load(
+            "equals",
+            "tracks\nlength = 2\nsky_map = a=b.hdr\npalette @ 0 step = 0 1 1 1\n\
+             palette @ 1 step = 0 0 0 0, 1 1 1 1\n",
+        )
+        .unwrap();
+        let start = tracks.interpolate(0.0, false);
+        assert_eq!(start.find("sky_map").unwrap_text(), "a=b.hdr");
+        assert_eq!(start.find("palette").unwrap_text(), "0 1 1 1");
+        let end = tracks.interpolate(0.75, false);
+        assert_eq!(end.find("palette").unwrap_text(), "0 0 0 0, 1 1 1 1");
+    }
+}
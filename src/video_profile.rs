@@ -0,0 +1,374 @@
+use crate::Error;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputKind {
+    PngSeq,
+    Gif,
+    Apng,
+    Ffmpeg,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+    ProRes,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("h264") {
+            Ok(Codec::H264)
+        } else if s.eq_ignore_ascii_case("h265") || s.eq_ignore_ascii_case("hevc") {
+            Ok(Codec::H265)
+        } else if s.eq_ignore_ascii_case("vp9") {
+            Ok(Codec::Vp9)
+        } else if s.eq_ignore_ascii_case("av1") {
+            Ok(Codec::Av1)
+        } else if s.eq_ignore_ascii_case("prores") {
+            Ok(Codec::ProRes)
+        } else {
+            Err(format!("Invalid codec: {}", s).into())
+        }
+    }
+}
+
+impl Codec {
+    fn encoder(self) -> &'static str {
+        match self {
+            Codec::H264 => "libx264",
+            Codec::H265 => "libx265",
+            Codec::Vp9 => "libvpx-vp9",
+            Codec::Av1 => "libaom-av1",
+            Codec::ProRes => "prores_ks",
+        }
+    }
+
+    fn container(self) -> &'static str {
+        match self {
+            Codec::H264 | Codec::H265 | Codec::Av1 => "mp4",
+            Codec::Vp9 => "webm",
+            Codec::ProRes => "mov",
+        }
+    }
+
+    fn pixel_format(self) -> &'static str {
+        match self {
+            Codec::H264 | Codec::H265 | Codec::Vp9 | Codec::Av1 => "yuv420p",
+            Codec::ProRes => "yuv422p10le",
+        }
+    }
+
+    // None if the codec can't store alpha
+    fn alpha_pixel_format(self) -> Option<&'static str> {
+        match self {
+            Codec::Vp9 => Some("yuva420p"),
+            Codec::ProRes => Some("yuva444p10le"),
+            Codec::H264 | Codec::H265 | Codec::Av1 => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Quality {
+    Crf(u32),
+    Bitrate(String),
+}
+
+// Everything needed to turn a stream of frames into an output file
+#[derive(Clone, Debug)]
+pub struct VideoProfile {
+    pub kind: OutputKind,
+    pub fps: f64,
+    pub codec: Option<Codec>,
+    pub quality: Option<Quality>,
+    pub pixel_format: Option<String>,
+    // used instead of a missing pixel_format when encoding a png sequence
+    pngseq_pixel_format: Option<String>,
+    pub container: Option<String>,
+    pub output_name: String,
+    pub extra_args: Vec<String>,
+    // gif only
+    pub dither: bool,
+    // transparent background
+    pub alpha: bool,
+}
+
+impl VideoProfile {
+    fn new(kind: OutputKind) -> Self {
+        Self {
+            kind,
+            fps: 60.0,
+            codec: None,
+            quality: None,
+            pixel_format: None,
+            pngseq_pixel_format: None,
+            container: None,
+            output_name: "video".to_string(),
+            extra_args: Vec::new(),
+            dither: true,
+            alpha: false,
+        }
+    }
+
+    fn with_codec(codec: Codec, quality: Quality) -> Self {
+        Self {
+            codec: Some(codec),
+            quality: Some(quality),
+            ..Self::new(OutputKind::Ffmpeg)
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "pngseq" => Self::new(OutputKind::PngSeq),
+            "gif" => Self {
+                fps: 50.0,
+                output_name: "output".to_string(),
+                ..Self::new(OutputKind::Gif)
+            },
+            "apng" => Self::new(OutputKind::Apng),
+            // video is corrupted otherwise for some reason
+            "mp4" => Self {
+                pngseq_pixel_format: Some("yuv420p".to_string()),
+                ..Self::new(OutputKind::Ffmpeg)
+            },
+            "twitter" => Self::with_codec(Codec::H264, Quality::Bitrate("2048K".to_string())),
+            "h264" => Self::with_codec(Codec::H264, Quality::Crf(18)),
+            "h265" => Self::with_codec(Codec::H265, Quality::Crf(20)),
+            "vp9" => Self::with_codec(Codec::Vp9, Quality::Crf(24)),
+            "av1" => Self::with_codec(Codec::Av1, Quality::Crf(24)),
+            "prores" => Self {
+                codec: Some(Codec::ProRes),
+                ..Self::new(OutputKind::Ffmpeg)
+            },
+            _ => return None,
+        })
+    }
+
+    // Looks up a preset by name, user presets in `presets_file` take priority over builtin ones.
+    pub fn find(name: &str, presets_file: &str) -> Result<Self, Error> {
+        Self::find_nested(name, presets_file, 0)
+    }
+
+    fn find_nested(name: &str, presets_file: &str, depth: usize) -> Result<Self, Error> {
+        if depth > 16 {
+            return Err(format!("Video preset {} has a cyclic base", name).into());
+        }
+        if Path::new(presets_file).exists() {
+            if let Some(profile) = Self::load_preset(name, presets_file, depth)? {
+                return Ok(profile);
+            }
+        }
+        Self::builtin(name).ok_or_else(|| format!("Unknown video preset: {}", name).into())
+    }
+
+    // Preset file format, presets separated by ---:
+    //   name = youtube
+    //   base = h264
+    //   fps = 30
+    //   crf = 16
+    //   args = -preset slow -tune grain
+    //   ---
+    fn load_preset(name: &str, presets_file: &str, depth: usize) -> Result<Option<Self>, Error> {
+        let reader = BufReader::new(File::open(presets_file)?);
+        let mut lines = Vec::new();
+        let mut found = false;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line == "---" {
+                if found {
+                    break;
+                }
+                lines.clear();
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid format in presets file: {}", line))?;
+            if key.trim() == "name" && value.trim().eq_ignore_ascii_case(name) {
+                found = true;
+            }
+            lines.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        if !found {
+            return Ok(None);
+        }
+        let base = lines
+            .iter()
+            .find(|(key, _)| key == "base")
+            .map_or("mp4", |(_, value)| value.as_str());
+        let mut profile = if base.eq_ignore_ascii_case(name) {
+            // a user preset overriding a builtin one of the same name
+            Self::builtin(base).ok_or_else(|| format!("Unknown video preset: {}", base))?
+        } else {
+            Self::find_nested(base, presets_file, depth + 1)?
+        };
+        for (key, value) in &lines {
+            if key != "name" && key != "base" {
+                profile.set(key, value)?;
+            }
+        }
+        Ok(Some(profile))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "fps" => self.fps = value.parse()?,
+            "codec" => self.codec = Some(value.parse()?),
+            "crf" => self.quality = Some(Quality::Crf(value.parse()?)),
+            "bitrate" => self.quality = Some(Quality::Bitrate(value.to_string())),
+            "pixel_format" => self.pixel_format = Some(value.to_string()),
+            "container" => self.container = Some(value.to_string()),
+            "output" => self.output_name = value.to_string(),
+            "args" => self
+                .extra_args
+                .extend(value.split_ascii_whitespace().map(str::to_string)),
+            "dither" => self.dither = value.parse()?,
+            "alpha" => self.alpha = value.parse()?,
+            _ => return Err(format!("Unknown video preset setting: {}", key).into()),
+        }
+        Ok(())
+    }
+
+    pub fn extension(&self) -> &str {
+        match self.kind {
+            OutputKind::PngSeq | OutputKind::Apng => "png",
+            OutputKind::Gif => "gif",
+            OutputKind::Ffmpeg => match (&self.container, self.codec) {
+                (Some(container), _) => container,
+                (None, Some(codec)) => codec.container(),
+                (None, None) => "mp4",
+            },
+        }
+    }
+
+    // Errors if alpha is requested from a video codec that would drop it
+    pub fn check_alpha(&self) -> Result<(), Error> {
+        if !self.alpha || self.kind != OutputKind::Ffmpeg {
+            return Ok(());
+        }
+        match self.codec {
+            Some(codec) if codec.alpha_pixel_format().is_some() => Ok(()),
+            _ => Err("Transparent video needs the vp9 or prores codec".into()),
+        }
+    }
+
+    pub fn fps_arg(&self) -> String {
+        self.fps.to_string()
+    }
+
+    // ffmpeg output arguments, everything between the inputs and the output file
+    pub fn encode_args(&self, from_pngseq: bool) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(codec) = self.codec {
+            args.extend(["-c:v".to_string(), codec.encoder().to_string()]);
+        }
+        let pixel_format = match (&self.pixel_format, self.codec) {
+            (Some(pixel_format), _) => Some(pixel_format.as_str()),
+            (None, Some(codec)) if self.alpha => codec.alpha_pixel_format(),
+            (None, Some(codec)) => Some(codec.pixel_format()),
+            (None, None) if from_pngseq => self.pngseq_pixel_format.as_deref(),
+            (None, None) => None,
+        };
+        if let Some(pixel_format) = pixel_format {
+            args.extend(["-pix_fmt".to_string(), pixel_format.to_string()]);
+        }
+        if self.alpha && self.codec == Some(Codec::ProRes) {
+            args.extend(["-profile:v".to_string(), "4444".to_string()]);
+        }
+        match (&self.quality, self.codec) {
+            (Some(Quality::Crf(crf)), Some(Codec::ProRes)) => {
+                args.extend(["-q:v".to_string(), crf.to_string()])
+            }
+            (Some(Quality::Crf(crf)), Some(Codec::Vp9)) => args.extend([
+                "-crf".to_string(),
+                crf.to_string(),
+                "-b:v".to_string(),
+                "0".to_string(),
+            ]),
+            (Some(Quality::Crf(crf)), _) => args.extend(["-crf".to_string(), crf.to_string()]),
+            (Some(Quality::Bitrate(bitrate)), _) => {
+                args.extend(["-b:v".to_string(), bitrate.clone()])
+            }
+            (None, _) => (),
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_PRESETS: &str = "clam5_test_no_presets.clam5";
+
+    fn with_presets(name: &str, text: &str, preset: &str) -> Result<VideoProfile, Error> {
+        let path = std::env::temp_dir().join(format!("clam5_test_{}.clam5", name));
+        std::fs::write(&path, text).unwrap();
+        let result = VideoProfile::find(preset, path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn builtin_presets() {
+        for name in ["mp4", "twitter", "h264", "prores", "pngseq", "gif"] {
+            assert!(VideoProfile::find(name, NO_PRESETS).is_ok(), "{}", name);
+        }
+        assert_eq!(
+            VideoProfile::find("h265", NO_PRESETS).unwrap().codec,
+            Some(Codec::H265)
+        );
+        assert!(VideoProfile::find("mp5", NO_PRESETS).is_err());
+    }
+
+    #[test]
+    fn user_presets() {
+        let presets =
+            "name = youtube\nbase = h264\ncrf = 16\n---\nname = h265\nbase = h265\nfps = 30\n";
+        let youtube = with_presets("youtube", presets, "youtube").unwrap();
+        assert_eq!(youtube.codec, Some(Codec::H264));
+        assert_eq!(youtube.quality, Some(Quality::Crf(16)));
+        // overrides the builtin preset of the same name, based on it
+        let h265 = with_presets("h265", presets, "h265").unwrap();
+        assert_eq!(h265.codec, Some(Codec::H265));
+        assert_eq!(h265.fps, 30.0);
+        let cyclic = "name = a\nbase = b\n---\nname = b\nbase = a\n";
+        assert!(with_presets("cyclic", cyclic, "a").is_err());
+    }
+
+    #[test]
+    fn names_ignore_case() {
+        for name in ["MP4", "H264", "ProRes"] {
+            assert!(VideoProfile::find(name, NO_PRESETS).is_ok(), "{}", name);
+        }
+        let presets = "name = YouTube\nbase = H264\ncrf = 16\n";
+        let youtube = with_presets("youtube_case", presets, "youtube").unwrap();
+        assert_eq!(youtube.codec, Some(Codec::H264));
+    }
+
+    #[test]
+    fn mp4_pixel_format_only_for_png_sequences() {
+        let mp4 = VideoProfile::find("mp4", NO_PRESETS).unwrap();
+        assert_eq!(mp4.encode_args(true), ["-pix_fmt", "yuv420p"]);
+        assert!(mp4.encode_args(false).is_empty());
+        let mut yuv444 = mp4.clone();
+        yuv444.set("pixel_format", "yuv444p").unwrap();
+        assert_eq!(yuv444.encode_args(false), ["-pix_fmt", "yuv444p"]);
+    }
+}